@@ -0,0 +1,128 @@
+//! Compares the BVH layouts, run with `cargo bench --bench bvh`
+
+use rand::Rng;
+use rusty_path::bvh::{BVHNode, FlatBVH};
+use rusty_path::hittable::{Hittable, Lambertian, Material, Sphere};
+use rusty_path::mesh::{Mesh, TriangleMesh};
+use rusty_path::wide_bvh::{SimdLevel, BVH4, BVH8};
+use rusty_path::texture::ConstantTexture;
+use rusty_path::vec3::{random_unit_vector, Vec3};
+use rusty_path::{presets, random, Ray};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const RAY_COUNT: usize = 200_000;
+
+fn material() -> Arc<dyn Material> {
+    Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new_fill(0.5)))))
+}
+
+/// Small spheres scattered in a 100 unit cube
+fn sphere_cloud(count: usize) -> Vec<Arc<dyn Hittable>> {
+    let material = material();
+    let mut rng = random::rng();
+    (0..count).map(|_| {
+        let center = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 100.0 - Vec3::new_fill(50.0);
+        Arc::new(Sphere::new(center, 0.2 + rng.gen::<f32>(), material.clone())) as Arc<dyn Hittable>
+    }).collect()
+}
+
+/// Triangles of a UV sphere with radius 50, `rings` squared times two of them
+fn tessellated_sphere(rings: usize) -> Vec<Arc<dyn Hittable>> {
+    let mut positions = vec![];
+    for i in 0..=rings {
+        let theta = std::f32::consts::PI * i as f32 / rings as f32;
+        for j in 0..rings {
+            let phi = 2.0 * std::f32::consts::PI * j as f32 / rings as f32;
+            positions.push(50.0 * Vec3::new(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin()));
+        }
+    }
+
+    let mut indices = vec![];
+    for i in 0..rings {
+        for j in 0..rings {
+            let a = (i * rings + j) as u32;
+            let b = (i * rings + (j + 1) % rings) as u32;
+            let c = ((i + 1) * rings + (j + 1) % rings) as u32;
+            let d = ((i + 1) * rings + j) as u32;
+            indices.push([a, b, c]);
+            indices.push([a, c, d]);
+        }
+    }
+
+    TriangleMesh::triangles(&Arc::new(Mesh::new(positions, None, None, indices, material())))
+}
+
+/// Rays starting inside the cube around the origin going in random directions
+fn random_rays(count: usize) -> Vec<Ray> {
+    let mut rng = random::rng();
+    (0..count).map(|_| {
+        let origin = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 80.0 - Vec3::new_fill(40.0);
+        Ray::new(origin, random_unit_vector(), 0.0)
+    }).collect()
+}
+
+/// Returns the run time and a checksum so both hierarchies can be checked to agree
+fn trace(world: &dyn Hittable, rays: &[Ray]) -> (Duration, f64) {
+    let start = Instant::now();
+    let mut checksum = 0.0;
+    for r in rays {
+        if let Some(hit) = world.hit(r, 0.001, f32::MAX) {
+            checksum += hit.t as f64;
+        }
+    }
+    (start.elapsed(), checksum)
+}
+
+fn trace_shadows(world: &dyn Hittable, rays: &[Ray]) -> (Duration, usize) {
+    let start = Instant::now();
+    let occluded = rays.iter().filter(|r| world.occluded(r, 0.001, 30.0)).count();
+    (start.elapsed(), occluded)
+}
+
+fn mrays(duration: Duration, count: usize) -> f64 {
+    count as f64 / duration.as_secs_f64() / 1e6
+}
+
+fn report(label: &str, world: &dyn Hittable, build: Duration, rays: &[Ray], reference: Option<(f64, usize)>) -> (f64, usize) {
+    let (time, checksum) = trace(world, rays);
+    let (shadow_time, occluded) = trace_shadows(world, rays);
+    if let Some((reference_sum, reference_occluded)) = reference {
+        assert!((checksum - reference_sum).abs() <= 1e-6 * reference_sum.abs(), "{}: closest hits differ", label);
+        assert_eq!(occluded, reference_occluded, "{}: occlusion results differ", label);
+    }
+
+    println!("  {:<12} build {:>10.2?}  closest {:>7.2} Mrays/s  occluded {:>7.2} Mrays/s",
+        label, build, mrays(time, rays.len()), mrays(shadow_time, rays.len()));
+    (checksum, occluded)
+}
+
+fn timed<T, F: FnOnce() -> T>(f: F) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+fn bench(name: &str, objects: &[Arc<dyn Hittable>]) {
+    let rays = random_rays(RAY_COUNT);
+    println!("{} ({} primitives)", name, objects.len());
+
+    let (tree, build) = timed(|| BVHNode::new(objects, 0.0, 1.0).unwrap());
+    let reference = Some(report("BVHNode", &tree, build, &rays, None));
+    let (flat, build) = timed(|| FlatBVH::new(objects, 0.0, 1.0).unwrap());
+    report("FlatBVH", &flat, build, &rays, reference);
+
+    let (bvh4, build) = timed(|| BVH4::new(objects, 0.0, 1.0).unwrap());
+    report(&format!("BVH4 {:?}", bvh4.simd_level()), &bvh4, build, &rays, reference);
+    let (mut bvh8, build) = timed(|| BVH8::new(objects, 0.0, 1.0).unwrap());
+    report(&format!("BVH8 {:?}", bvh8.simd_level()), &bvh8, build, &rays, reference);
+    bvh8.set_simd_level(SimdLevel::Scalar);
+    report("BVH8 Scalar", &bvh8, build, &rays, reference);
+}
+
+fn main() {
+    random::seed(1);
+    bench("random preset", &presets::preset("random").unwrap().objects);
+    bench("sphere cloud", &sphere_cloud(100_000));
+    bench("tessellated sphere", &tessellated_sphere(300));
+}
@@ -33,11 +33,20 @@ impl AABB {
         &self.min
     }
 
-    // pub fn max(&self) -> &Vec3 {
-    //     &self.max
-    // }
+    pub fn max(&self) -> &Vec3 {
+        &self.max
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
 
-    pub fn hit(&self, r: &Ray, tmin: f32, tmax: f32) -> bool {
+    pub fn hit(&self, r: &Ray, mut tmin: f32, mut tmax: f32) -> bool {
         for a in 0..3 {
             let inv_d = 1.0 / r.direction().v()[a];
             let mut t0 = (self.min.v()[a] - r.origin().v()[a]) * inv_d;
@@ -45,15 +54,27 @@ impl AABB {
             if inv_d < 0.0 {
                 mem::swap(&mut t0, &mut t1);
             }
-            let tmin = if t0 > tmin { t0 } else { tmin };
-            let tmax = if t1 < tmax { t1 } else { tmax };
-            if tmax <= tmin {
+            // Narrowed on every axis, the ray is inside the box where all three slabs overlap
+            tmin = if t0 > tmin { t0 } else { tmin };
+            tmax = if t1 < tmax { t1 } else { tmax };
+            // Flat boxes (e.g. walls) have tmin == tmax at large distances even when padded
+            if tmax < tmin {
                 return false
             }
         }
         true
     }
 
+    /// Grows the box on axes where it is thinner than `delta`
+    pub fn pad(&mut self, delta: f32) {
+        for i in 0..3 {
+            if self.max.v()[i] - self.min.v()[i] < delta {
+                self.min.set(i, self.min.v()[i] - delta * 0.5);
+                self.max.set(i, self.max.v()[i] + delta * 0.5);
+            }
+        }
+    }
+
     pub fn add(&mut self, other: &AABB) {
         for i in 0..3 {
             self.min.set(i, self.min.v()[i].min(other.min.v()[i]));
@@ -0,0 +1,68 @@
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::{Hittable, HitRecord, HittableList};
+use crate::bvh::{BVHUpdate, FlatBVH};
+use std::sync::Arc;
+
+/// Objects with a bounding box go into a BVH, unbounded ones like planes are tested one by one next to it
+pub struct Aggregate {
+    bvh: Option<FlatBVH>,
+    /// Unbounded objects, and all of them when there is no BVH
+    others: HittableList,
+}
+
+impl Aggregate {
+    /// Bounding boxes cover the time interval t0..t1, without `use_bvh` every object is tested for every ray
+    pub fn new(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32, use_bvh: bool) -> Aggregate {
+        let mut bounded = vec![];
+        let mut unbounded = vec![];
+        for object in objects {
+            match object.bouding_box(t0, t1) {
+                Some(_) => bounded.push(object.clone()),
+                None => unbounded.push(object.clone()),
+            }
+        }
+
+        if use_bvh && bounded.len() >= 2 {
+            Aggregate {
+                bvh: Some(FlatBVH::new(&bounded, t0, t1).expect("Only bounded objects go into the BVH")),
+                others: HittableList { items: unbounded }
+            }
+        } else {
+            Aggregate {
+                bvh: None,
+                others: HittableList { items: objects.to_vec() }
+            }
+        }
+    }
+
+    /// Refits or rebuilds the BVH for the time interval t0..t1, None without a BVH
+    pub fn update(&mut self, t0: f32, t1: f32) -> Option<BVHUpdate> {
+        self.bvh.as_mut().map(|bvh| bvh.update(t0, t1))
+    }
+
+    pub fn bvh(&self) -> Option<&FlatBVH> {
+        self.bvh.as_ref()
+    }
+}
+
+impl Hittable for Aggregate {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let bvh_hit = self.bvh.as_ref().and_then(|bvh| bvh.hit(r, t_min, t_max));
+        let closest = bvh_hit.as_ref().map_or(t_max, |hit| hit.t);
+        self.others.hit(r, t_min, closest).or(bvh_hit)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.bvh.as_ref().is_some_and(|bvh| bvh.occluded(r, t_min, t_max)) || self.others.occluded(r, t_min, t_max)
+    }
+
+    /// None as soon as one object is unbounded
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        let others = self.others.bouding_box(t0, t1)?;
+        match &self.bvh {
+            Some(bvh) => Some(AABB::new_surrounding(others, &bvh.bouding_box(t0, t1)?)),
+            None => Some(others),
+        }
+    }
+}
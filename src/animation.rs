@@ -0,0 +1,265 @@
+use crate::vec3::*;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::*;
+use crate::transform::*;
+use std::sync::Arc;
+
+/// Transforms sampled per interpolated segment when bounding the motion
+const BBOX_SUBSTEPS: usize = 16;
+
+/// Transform at one point in time, kept decomposed so rotations can be interpolated
+#[derive(Copy, Clone, Debug)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vec3,
+    pub rotation: Quaternion,
+    pub scale: Vec3,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, translation: Vec3, rotation: Quaternion, scale: Vec3) -> Keyframe {
+        Keyframe {
+            time,
+            translation,
+            rotation,
+            scale
+        }
+    }
+
+    /// Scales, then rotates, then translates
+    pub fn to_matrix(&self) -> Matrix4 {
+        Matrix4::translation(self.translation) * self.rotation.to_matrix() * Matrix4::scaling(self.scale)
+    }
+
+    /// Inverse of `to_matrix` built from the parts instead of a general inverse
+    pub fn to_inverse_matrix(&self) -> Matrix4 {
+        let inverse_scale = Vec3::new(1.0 / self.scale.x(), 1.0 / self.scale.y(), 1.0 / self.scale.z());
+        Matrix4::scaling(inverse_scale) * self.rotation.conjugate().to_matrix() * Matrix4::translation(-self.translation)
+    }
+}
+
+/// Transform changing over time, constant before the first and after the last keyframe
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>,
+}
+
+impl AnimatedTransform {
+    /// Keyframes need increasing times and non-zero scales. The scale is interpolated linearly,
+    /// so neighbouring keys cannot flip its sign on any axis, it would pass through zero
+    pub fn new(keyframes: Vec<Keyframe>) -> AnimatedTransform {
+        assert!(!keyframes.is_empty(), "Animation needs at least one keyframe");
+        assert!(keyframes.windows(2).all(|pair| pair[0].time < pair[1].time), "Keyframe times have to increase");
+        assert!(keyframes.iter().all(|key| key.scale.x() * key.scale.y() * key.scale.z() != 0.0), "Keyframe scale can not be zero");
+        assert!(
+            keyframes.windows(2).all(|pair| (0..3).all(|i| pair[0].scale.v()[i].signum() == pair[1].scale.v()[i].signum())),
+            "Keyframe scale can not change sign between neighbouring keys"
+        );
+        AnimatedTransform {
+            keyframes
+        }
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    /// Translation and scale are interpolated linearly and the rotation by slerp
+    pub fn interpolate(&self, time: f32) -> Keyframe {
+        let next = self.keyframes.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return Keyframe { time, ..self.keyframes[0] };
+        }
+        if next == self.keyframes.len() {
+            return Keyframe { time, ..self.keyframes[next - 1] };
+        }
+
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe {
+            time,
+            translation: (1.0 - t) * a.translation + t * b.translation,
+            rotation: a.rotation.slerp(&b.rotation, t),
+            scale: (1.0 - t) * a.scale + t * b.scale
+        }
+    }
+
+    /// Object to world and world to object matrices at `time`
+    pub fn matrices(&self, time: f32) -> (Matrix4, Matrix4) {
+        let key = self.interpolate(time);
+        (key.to_matrix(), key.to_inverse_matrix())
+    }
+
+    /// Box around `bbox` moved by the animation for every time in t0..t1.
+    /// The transformed box is sampled along each segment and padded by half the furthest
+    /// a corner can travel between two samples, bounding the paths rotations take between them.
+    pub fn motion_bbox(&self, bbox: &AABB, t0: f32, t1: f32) -> AABB {
+        let mut times = vec![t0];
+        times.extend(self.keyframes.iter().map(|key| key.time).filter(|&time| time > t0 && time < t1));
+        times.push(t1);
+
+        let radius = (0..8).map(|corner| {
+            Vec3::new(
+                if corner & 1 == 0 { bbox.min().x() } else { bbox.max().x() },
+                if corner & 2 == 0 { bbox.min().y() } else { bbox.max().y() },
+                if corner & 4 == 0 { bbox.min().z() } else { bbox.max().z() }
+            ).length()
+        }).fold(0.0, f32::max);
+
+        let mut result = self.interpolate(t0).to_matrix().transform_bbox(bbox);
+        let mut padding: f32 = 0.0;
+        for span in times.windows(2) {
+            let (start, end) = (span[0], span[1]);
+            for step in 1..=BBOX_SUBSTEPS {
+                let time = start + (end - start) * step as f32 / BBOX_SUBSTEPS as f32;
+                result.add(&self.interpolate(time).to_matrix().transform_bbox(bbox));
+            }
+
+            // Spans never cross a keyframe so they are inside one segment or outside all of them
+            let next = self.keyframes.partition_point(|key| key.time <= start);
+            if next == 0 || next == self.keyframes.len() {
+                continue;
+            }
+            let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+            let max_scale = [a.scale, b.scale].iter()
+                .map(|s| s.x().abs().max(s.y().abs()).max(s.z().abs()))
+                .fold(0.0, f32::max);
+            let travel = (b.translation - a.translation).length()
+                + a.rotation.angle_to(&b.rotation) * max_scale * radius
+                + (b.scale - a.scale).length() * radius;
+            let step_fraction = (end - start) / BBOX_SUBSTEPS as f32 / (b.time - a.time);
+            padding = padding.max(travel * step_fraction * 0.5);
+        }
+
+        let delta = Vec3::new_fill(padding);
+        AABB::new(*result.min() - delta, *result.max() + delta)
+    }
+}
+
+/// Any hittable moved by keyframes, the ray time picks the transform so the shutter blurs the motion
+pub struct Animated {
+    object: Arc<dyn Hittable>,
+    animation: Arc<AnimatedTransform>,
+}
+
+impl Animated {
+    /// The animation is shared so many objects can follow the same keyframes
+    pub fn new(object: Arc<dyn Hittable>, animation: Arc<AnimatedTransform>) -> Animated {
+        Animated {
+            object,
+            animation
+        }
+    }
+
+    pub fn animation(&self) -> &Arc<AnimatedTransform> {
+        &self.animation
+    }
+}
+
+impl Hittable for Animated {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (object_to_world, world_to_object) = self.animation.matrices(r.time());
+        hit_transformed(&*self.object, &object_to_world, &world_to_object, r, t_min, t_max)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        let world_to_object = self.animation.interpolate(r.time()).to_inverse_matrix();
+        occluded_transformed(&*self.object, &world_to_object, r, t_min, t_max)
+    }
+
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.object.bouding_box(t0, t1).map(|bbox| self.animation.motion_bbox(&bbox, t0, t1))
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let world_to_object = self.animation.interpolate(time).to_inverse_matrix();
+        pdf_value_transformed(&*self.object, &world_to_object, origin, direction, time)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        let (object_to_world, world_to_object) = self.animation.matrices(time);
+        sample_direction_transformed(&*self.object, &object_to_world, &world_to_object, origin, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Vec3, b: &Vec3) -> bool {
+        (*a - *b).length() < 1e-5
+    }
+
+    fn animation() -> AnimatedTransform {
+        AnimatedTransform::new(vec![
+            Keyframe::new(1.0, Vec3::new(0.0, 1.0, 0.0), Quaternion::identity(), Vec3::one()),
+            Keyframe::new(2.0, Vec3::new(4.0, 1.0, 0.0), Quaternion::from_axis_angle(Vec3::up(), 90.0), Vec3::new(3.0, 1.0, 0.5)),
+            Keyframe::new(4.0, Vec3::new(4.0, 1.0, -2.0), Quaternion::from_axis_angle(Vec3::up(), 90.0), Vec3::new(1.0, 2.0, 0.5)),
+        ])
+    }
+
+    #[test]
+    fn holds_the_first_and_last_key() {
+        let animation = animation();
+        let before = animation.interpolate(-3.0);
+        assert_eq!(before.time, -3.0);
+        assert!(close(&before.translation, &Vec3::new(0.0, 1.0, 0.0)) && close(&before.scale, &Vec3::one()));
+        let after = animation.interpolate(10.0);
+        assert!(close(&after.translation, &Vec3::new(4.0, 1.0, -2.0)) && close(&after.scale, &Vec3::new(1.0, 2.0, 0.5)));
+    }
+
+    #[test]
+    fn interpolates_within_segments() {
+        let animation = animation();
+        for key in animation.keyframes() {
+            let at_key = animation.interpolate(key.time);
+            assert!(close(&at_key.translation, &key.translation) && close(&at_key.scale, &key.scale));
+        }
+
+        let mid = animation.interpolate(1.5);
+        assert!(close(&mid.translation, &Vec3::new(2.0, 1.0, 0.0)));
+        assert!(close(&mid.scale, &Vec3::new(2.0, 1.0, 0.75)));
+        // Halfway through a quarter turn
+        let x = mid.rotation.to_matrix().transform_vector(&Vec3::right());
+        let half = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(close(&x, &Vec3::new(half, 0.0, -half)), "{:?}", x);
+
+        let quarter = animation.interpolate(2.5);
+        assert!(close(&quarter.translation, &Vec3::new(4.0, 1.0, -0.5)));
+        assert!(close(&quarter.scale, &Vec3::new(2.5, 1.25, 0.5)));
+    }
+
+    #[test]
+    fn matrices_are_inverse_at_all_times() {
+        let animation = animation();
+        for i in 0..=50 {
+            let (object_to_world, world_to_object) = animation.matrices(i as f32 * 0.1);
+            let product = object_to_world * world_to_object;
+            for row in 0..4 {
+                for column in 0..4 {
+                    let expected = if row == column { 1.0 } else { 0.0 };
+                    assert!((product.get(row, column) - expected).abs() < 1e-5, "at {}: {:?}", i as f32 * 0.1, product);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "change sign")]
+    fn rejects_scales_changing_sign() {
+        // Halfway the scale would be zero on x
+        AnimatedTransform::new(vec![
+            Keyframe::new(0.0, Vec3::zero(), Quaternion::identity(), Vec3::one()),
+            Keyframe::new(1.0, Vec3::zero(), Quaternion::identity(), Vec3::new(-1.0, 1.0, 1.0)),
+        ]);
+    }
+
+    #[test]
+    fn accepts_negative_scales_kept_over_keys() {
+        let animation = AnimatedTransform::new(vec![
+            Keyframe::new(0.0, Vec3::zero(), Quaternion::identity(), Vec3::new(-1.0, 1.0, 1.0)),
+            Keyframe::new(1.0, Vec3::zero(), Quaternion::identity(), Vec3::new(-3.0, 1e-20, 1.0)),
+        ]);
+        assert!(animation.interpolate(0.5).scale.x() < 0.0);
+    }
+}
@@ -0,0 +1,769 @@
+use crate::vec3::Vec3;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::{Hittable, HitRecord};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+/// Cost of visiting a node relative to intersecting one primitive
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// Deeper nodes are split at the median, which bounds the tree depth by this plus log2 of the primitive count
+const MAX_SAH_DEPTH: usize = 32;
+
+/// Bound on the tree depth and thus the traversal stack size
+pub(crate) const MAX_DEPTH: usize = MAX_SAH_DEPTH + 32;
+
+/// Nodes with at least this many primitives build their children on separate rayon tasks
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Nodes with at least this many primitives also bin them in parallel, only the top levels are that large
+const PARALLEL_BINNING_THRESHOLD: usize = 65536;
+
+/// Primitives binned by one task when binning in parallel
+const BINNING_CHUNK: usize = 16384;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BVHSettings {
+    /// Leaves never hold more primitives, smaller nodes become leaves when splitting does not pay off
+    pub max_leaf_size: usize,
+    /// Number of buckets along each axis in which split positions are evaluated
+    pub bins: usize,
+    /// A refitted tree is rebuilt once its SAH cost grew by this factor over the cost right after building
+    pub rebuild_threshold: f32,
+}
+
+impl Default for BVHSettings {
+    fn default() -> BVHSettings {
+        BVHSettings {
+            max_leaf_size: 4,
+            bins: 16,
+            rebuild_threshold: 1.5
+        }
+    }
+}
+
+impl BVHSettings {
+    /// Refitting keeps the topology, which gets worse the more primitives move relative to each other
+    pub fn needs_rebuild(&self, built_cost: f32, refitted_cost: f32) -> bool {
+        refitted_cost > built_cost * self.rebuild_threshold
+    }
+}
+
+/// What `FlatBVH::update` did to follow the motion
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BVHUpdate {
+    Refit,
+    Rebuild,
+}
+
+/// Tree over primitive indices, the hierarchies used for tracing are made from it
+pub(crate) enum BuildNode {
+    /// Primitives `order[first..first + count]`
+    Leaf { bbox: AABB, first: usize, count: usize },
+    /// `axis` is the one along which the children were split
+    Inner { bbox: AABB, axis: usize, left: Box<BuildNode>, right: Box<BuildNode> },
+}
+
+pub(crate) struct BuildTree {
+    pub root: BuildNode,
+    /// Primitive indices grouped by leaves
+    pub order: Vec<usize>,
+}
+
+struct Primitive {
+    index: usize,
+    bbox: AABB,
+    centroid: Vec3,
+}
+
+/// Binned SAH build (Wald 2007), the result only depends on the input boxes.
+/// Large subtrees are built in parallel on the current rayon thread pool.
+pub(crate) fn build_tree(bboxes: &[AABB], settings: &BVHSettings) -> BuildTree {
+    assert!(!bboxes.is_empty(), "BVH needs at least one primitive");
+    assert!(bboxes.len() <= u32::MAX as usize, "BVH primitive count does not fit 32 bits");
+
+    let mut primitives: Vec<Primitive> = bboxes.par_iter()
+        .enumerate()
+        .map(|(index, bbox)| Primitive { index, bbox: *bbox, centroid: bbox.centroid() })
+        .collect();
+    let root = build_node(&mut primitives, 0, 0, settings);
+
+    BuildTree {
+        root,
+        order: primitives.par_iter().map(|p| p.index).collect()
+    }
+}
+
+/// Box around the primitives and box around their centroids
+fn primitive_bounds(primitives: &[Primitive]) -> (AABB, AABB) {
+    let bounds = |primitives: &[Primitive]| {
+        let mut bbox = AABB::new_empty();
+        let mut centroid_bounds = AABB::new_empty();
+        for p in primitives {
+            bbox.add(&p.bbox);
+            centroid_bounds.add(&AABB::new(p.centroid, p.centroid));
+        }
+        (bbox, centroid_bounds)
+    };
+
+    if primitives.len() < PARALLEL_BINNING_THRESHOLD {
+        return bounds(primitives);
+    }
+    primitives.par_chunks(BINNING_CHUNK)
+        .map(bounds)
+        .reduce(|| (AABB::new_empty(), AABB::new_empty()), |(a, ca), (b, cb)| {
+            (AABB::new_surrounding(a, &b), AABB::new_surrounding(ca, &cb))
+        })
+}
+
+fn build_node(primitives: &mut [Primitive], first: usize, depth: usize, settings: &BVHSettings) -> BuildNode {
+    let count = primitives.len();
+    let (bbox, centroid_bounds) = primitive_bounds(primitives);
+
+    if count == 1 {
+        return BuildNode::Leaf { bbox, first, count };
+    }
+
+    let max_leaf_size = settings.max_leaf_size.clamp(1, u16::MAX as usize);
+    let split = if depth < MAX_SAH_DEPTH {
+        find_split(primitives, &bbox, &centroid_bounds, settings)
+    } else {
+        None
+    };
+
+    let leaf_cost = count as f32;
+    let (mid, axis) = match split {
+        Some(split) if split.cost < leaf_cost || count > max_leaf_size => {
+            (partition(primitives, &centroid_bounds, &split, settings.bins), split.axis)
+        },
+        Some(_) => return BuildNode::Leaf { bbox, first, count },
+        None if count <= max_leaf_size => return BuildNode::Leaf { bbox, first, count },
+        // All centroids at one spot, a flat parent or a too deep tree, SAH has nothing to go on
+        None => median_split(primitives, &centroid_bounds),
+    };
+
+    let (left, right) = primitives.split_at_mut(mid);
+    let (left, right) = if count >= PARALLEL_BUILD_THRESHOLD {
+        rayon::join(
+            || build_node(left, first, depth + 1, settings),
+            || build_node(right, first + mid, depth + 1, settings)
+        )
+    } else {
+        (build_node(left, first, depth + 1, settings), build_node(right, first + mid, depth + 1, settings))
+    };
+    BuildNode::Inner {
+        bbox,
+        axis,
+        left: Box::new(left),
+        right: Box::new(right)
+    }
+}
+
+struct Split {
+    cost: f32,
+    axis: usize,
+    /// Bins up to and including this one go to the left child
+    bin: usize,
+}
+
+fn bin_index(centroid: &Vec3, centroid_bounds: &AABB, axis: usize, bins: usize) -> usize {
+    let min = centroid_bounds.min().v()[axis];
+    let extent = centroid_bounds.max().v()[axis] - min;
+    let bin = ((centroid.v()[axis] - min) / extent * bins as f32) as usize;
+    bin.min(bins - 1)
+}
+
+/// Cheapest split between bins, ties go to the lower axis and position so builds are reproducible
+fn find_split(primitives: &[Primitive], bbox: &AABB, centroid_bounds: &AABB, settings: &BVHSettings) -> Option<Split> {
+    let parent_area = bbox.surface_area();
+    if parent_area <= 0.0 {
+        return None;
+    }
+
+    let bins = settings.bins.max(2);
+    let mut best: Option<Split> = None;
+    for axis in 0..3 {
+        if centroid_bounds.max().v()[axis] <= centroid_bounds.min().v()[axis] {
+            continue;
+        }
+
+        let (bin_bounds, bin_counts) = bin_primitives(primitives, centroid_bounds, axis, bins);
+
+        // Area and count of everything right of each split, sweeping from the end
+        let mut right_area = vec![0.0; bins];
+        let mut right_count = vec![0usize; bins];
+        let mut bounds = AABB::new_empty();
+        let mut n = 0;
+        for bin in (1..bins).rev() {
+            bounds.add(&bin_bounds[bin]);
+            n += bin_counts[bin];
+            right_count[bin] = n;
+            if n > 0 {
+                right_area[bin] = bounds.surface_area();
+            }
+        }
+
+        let mut bounds = AABB::new_empty();
+        let mut n = 0;
+        for bin in 0..bins - 1 {
+            bounds.add(&bin_bounds[bin]);
+            n += bin_counts[bin];
+            let right_n = right_count[bin + 1];
+            if n == 0 || right_n == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST + (bounds.surface_area() * n as f32 + right_area[bin + 1] * right_n as f32) / parent_area;
+            if best.as_ref().map_or(true, |b| cost < b.cost) {
+                best = Some(Split { cost, axis, bin });
+            }
+        }
+    }
+
+    best
+}
+
+/// Bounds and counts of the primitives in each bin along `axis`, bins of chunks are merged
+/// for large nodes which gives the same result as binning everything at once
+fn bin_primitives(primitives: &[Primitive], centroid_bounds: &AABB, axis: usize, bins: usize) -> (Vec<AABB>, Vec<usize>) {
+    let bin = |primitives: &[Primitive]| {
+        let mut bin_bounds = vec![AABB::new_empty(); bins];
+        let mut bin_counts = vec![0usize; bins];
+        for p in primitives {
+            let bin = bin_index(&p.centroid, centroid_bounds, axis, bins);
+            bin_bounds[bin].add(&p.bbox);
+            bin_counts[bin] += 1;
+        }
+        (bin_bounds, bin_counts)
+    };
+
+    if primitives.len() < PARALLEL_BINNING_THRESHOLD {
+        return bin(primitives);
+    }
+    primitives.par_chunks(BINNING_CHUNK)
+        .map(bin)
+        .reduce(|| (vec![AABB::new_empty(); bins], vec![0; bins]), |(mut bounds, mut counts), (other_bounds, other_counts)| {
+            for i in 0..bins {
+                bounds[i].add(&other_bounds[i]);
+                counts[i] += other_counts[i];
+            }
+            (bounds, counts)
+        })
+}
+
+/// Moves primitives left of the split to the front, returns how many there are
+fn partition(primitives: &mut [Primitive], centroid_bounds: &AABB, split: &Split, bins: usize) -> usize {
+    let bins = bins.max(2);
+    let mut mid = 0;
+    for i in 0..primitives.len() {
+        if bin_index(&primitives[i].centroid, centroid_bounds, split.axis, bins) <= split.bin {
+            primitives.swap(i, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Halves by count along the longest centroid axis, the index breaks ties between equal centroids
+/// Returns the size of the first half and the axis
+fn median_split(primitives: &mut [Primitive], centroid_bounds: &AABB) -> (usize, usize) {
+    let extent = centroid_bounds.max() - centroid_bounds.min();
+    let axis = (0..3).fold(0, |best, axis| if extent.v()[axis] > extent.v()[best] { axis } else { best });
+    let mid = primitives.len() / 2;
+    // Only the halves matter, selecting the median is linear where sorting is not
+    primitives.select_nth_unstable_by(mid, |a, b| {
+        a.centroid.v()[axis].partial_cmp(&b.centroid.v()[axis])
+            .unwrap_or(Ordering::Equal)
+            .then(a.index.cmp(&b.index))
+    });
+    (mid, axis)
+}
+
+/// Bounding boxes of all objects over t0..t1, unbounded objects like planes cannot be put into a BVH
+pub(crate) fn object_bboxes(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32) -> Result<Vec<AABB>, String> {
+    objects.par_iter()
+        .enumerate()
+        .map(|(i, o)| o.bouding_box(t0, t1).ok_or_else(|| format!("object {} has no bounding box and cannot be put into a BVH", i)))
+        .collect()
+}
+
+/// Sum of node areas times their cost divided by the root area, flat roots cost as much as testing everything
+fn sah_cost(root_area: f32, area_cost: f32) -> f32 {
+    if root_area > 0.0 {
+        area_cost / root_area
+    } else {
+        f32::INFINITY
+    }
+}
+
+enum BVHContent {
+    Leaf(Vec<Arc<dyn Hittable>>),
+    Inner(Box<BVHNode>, Box<BVHNode>),
+}
+
+pub struct BVHNode {
+    bbox: AABB,
+    content: BVHContent,
+}
+
+impl BVHNode {
+    /// Bounding boxes cover the time interval t0..t1. Fails when an object has none,
+    /// `Aggregate` keeps such objects next to its BVH instead
+    pub fn new(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32) -> Result<BVHNode, String> {
+        BVHNode::with_settings(objects, t0, t1, &BVHSettings::default())
+    }
+
+    pub fn with_settings(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32, settings: &BVHSettings) -> Result<BVHNode, String> {
+        let bboxes = object_bboxes(objects, t0, t1)?;
+        let tree = build_tree(&bboxes, settings);
+        Ok(BVHNode::from_build_node(&tree.root, &tree.order, objects))
+    }
+
+    /// Recomputes the bounds bottom up for the time interval t0..t1, the tree structure stays the same
+    pub fn refit(&mut self, t0: f32, t1: f32) {
+        self.bbox = match &mut self.content {
+            BVHContent::Leaf(objects) => objects.iter().fold(AABB::new_empty(), |bbox, o| {
+                AABB::new_surrounding(bbox, &o.bouding_box(t0, t1).expect("No bounding box in BVHNode refit"))
+            }),
+            BVHContent::Inner(left, right) => {
+                left.refit(t0, t1);
+                right.refit(t0, t1);
+                AABB::new_surrounding(left.bbox, &right.bbox)
+            },
+        };
+    }
+
+    /// Expected cost of tracing a ray hitting the root, in the units of the build heuristic
+    pub fn sah_cost(&self) -> f32 {
+        sah_cost(self.bbox.surface_area(), self.area_cost())
+    }
+
+    /// Node areas weighted by their cost, not yet divided by the root area
+    fn area_cost(&self) -> f32 {
+        match &self.content {
+            BVHContent::Leaf(objects) => self.bbox.surface_area() * objects.len() as f32,
+            BVHContent::Inner(left, right) => self.bbox.surface_area() * TRAVERSAL_COST + left.area_cost() + right.area_cost(),
+        }
+    }
+
+    fn from_build_node(node: &BuildNode, order: &[usize], objects: &[Arc<dyn Hittable>]) -> BVHNode {
+        match node {
+            BuildNode::Leaf { bbox, first, count } => BVHNode {
+                bbox: *bbox,
+                content: BVHContent::Leaf(order[*first..first + count].iter().map(|&i| objects[i].clone()).collect())
+            },
+            BuildNode::Inner { bbox, left, right, .. } => BVHNode {
+                bbox: *bbox,
+                content: BVHContent::Inner(
+                    Box::new(BVHNode::from_build_node(left, order, objects)),
+                    Box::new(BVHNode::from_build_node(right, order, objects))
+                )
+            },
+        }
+    }
+}
+
+impl Hittable for BVHNode {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        if !self.bbox.hit(r, t_min, t_max) {
+            return None;
+        }
+
+        match &self.content {
+            BVHContent::Leaf(objects) => {
+                let mut result = None;
+                let mut closest = t_max;
+                for object in objects {
+                    if let Some(hit) = object.hit(r, t_min, closest) {
+                        closest = hit.t;
+                        result = Some(hit);
+                    }
+                }
+                result
+            },
+            BVHContent::Inner(left, right) => {
+                // Whatever the left child hits limits the search in the right one
+                let left_hit = left.hit(r, t_min, t_max);
+                let closest = left_hit.as_ref().map_or(t_max, |hit| hit.t);
+                right.hit(r, t_min, closest).or(left_hit)
+            },
+        }
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        if !self.bbox.hit(r, t_min, t_max) {
+            return false;
+        }
+
+        match &self.content {
+            BVHContent::Leaf(objects) => objects.iter().any(|o| o.occluded(r, t_min, t_max)),
+            BVHContent::Inner(left, right) => left.occluded(r, t_min, t_max) || right.occluded(r, t_min, t_max),
+        }
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        Some(self.bbox)
+    }
+}
+
+/// Node of the flattened hierarchy, two of them fit in a cache line
+#[repr(C, align(32))]
+struct FlatNode {
+    min: [f32; 3],
+    max: [f32; 3],
+    /// Leaves: first primitive, inner nodes: index of the second child, the first one directly follows its parent
+    offset: u32,
+    /// Zero for inner nodes
+    count: u16,
+    axis: u8,
+}
+
+const _: () = assert!(std::mem::size_of::<FlatNode>() == 32);
+
+impl FlatNode {
+    fn bbox(&self) -> AABB {
+        AABB::new(Vec3::new(self.min[0], self.min[1], self.min[2]), Vec3::new(self.max[0], self.max[1], self.max[2]))
+    }
+
+    fn set_bbox(&mut self, bbox: &AABB) {
+        self.min = *bbox.min().v();
+        self.max = *bbox.max().v();
+    }
+
+    fn hit(&self, origin: &[f32; 3], inv_dir: &[f32; 3], mut t_min: f32, mut t_max: f32) -> bool {
+        for a in 0..3 {
+            let t0 = (self.min[a] - origin[a]) * inv_dir[a];
+            let t1 = (self.max[a] - origin[a]) * inv_dir[a];
+            let (t0, t1) = if inv_dir[a] < 0.0 { (t1, t0) } else { (t0, t1) };
+            // max and min ignore the NaN of a ray running inside a slab plane
+            t_min = t0.max(t_min);
+            t_max = t1.min(t_max);
+            if t_max < t_min {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// BVH stored depth first in one array and traversed with an explicit stack, nearer children first
+pub struct FlatBVH {
+    nodes: Vec<FlatNode>,
+    /// Ordered so that every leaf references a contiguous range
+    primitives: Vec<Arc<dyn Hittable>>,
+    settings: BVHSettings,
+    /// SAH cost when the tree was last built, refits are measured against it
+    built_cost: f32,
+}
+
+impl FlatBVH {
+    /// Bounding boxes cover the time interval t0..t1. Fails when an object has none,
+    /// `Aggregate` keeps such objects next to its BVH instead
+    pub fn new(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32) -> Result<FlatBVH, String> {
+        FlatBVH::with_settings(objects, t0, t1, &BVHSettings::default())
+    }
+
+    pub fn with_settings(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32, settings: &BVHSettings) -> Result<FlatBVH, String> {
+        let bboxes = object_bboxes(objects, t0, t1)?;
+        let tree = build_tree(&bboxes, settings);
+
+        let mut nodes = Vec::new();
+        FlatBVH::flatten(&tree.root, &mut nodes);
+        let mut bvh = FlatBVH {
+            nodes,
+            primitives: tree.order.iter().map(|&i| objects[i].clone()).collect(),
+            settings: *settings,
+            built_cost: 0.0
+        };
+        bvh.built_cost = bvh.sah_cost();
+        Ok(bvh)
+    }
+
+    fn flatten(node: &BuildNode, nodes: &mut Vec<FlatNode>) -> usize {
+        let index = nodes.len();
+        let (bbox, offset, count, axis) = match node {
+            BuildNode::Leaf { bbox, first, count } => (bbox, *first as u32, *count as u16, 0),
+            BuildNode::Inner { bbox, axis, .. } => (bbox, 0, 0, *axis as u8),
+        };
+        nodes.push(FlatNode {
+            min: *bbox.min().v(),
+            max: *bbox.max().v(),
+            offset,
+            count,
+            axis
+        });
+
+        if let BuildNode::Inner { left, right, .. } = node {
+            FlatBVH::flatten(left, nodes);
+            let second = FlatBVH::flatten(right, nodes);
+            nodes[index].offset = second as u32;
+        }
+        index
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Recomputes the bounds bottom up for the time interval t0..t1, the tree structure stays the same
+    pub fn refit(&mut self, t0: f32, t1: f32) {
+        // Children are stored after their parent
+        for index in (0..self.nodes.len()).rev() {
+            let node = &self.nodes[index];
+            let bbox = if node.count > 0 {
+                let first = node.offset as usize;
+                self.primitives[first..first + node.count as usize].iter().fold(AABB::new_empty(), |bbox, p| {
+                    AABB::new_surrounding(bbox, &p.bouding_box(t0, t1).expect("No bounding box in FlatBVH refit"))
+                })
+            } else {
+                AABB::new_surrounding(self.nodes[index + 1].bbox(), &self.nodes[node.offset as usize].bbox())
+            };
+            self.nodes[index].set_bbox(&bbox);
+        }
+    }
+
+    /// Expected cost of tracing a ray hitting the root, in the units of the build heuristic
+    pub fn sah_cost(&self) -> f32 {
+        let area_cost = self.nodes.iter().map(|node| {
+            let cost = if node.count > 0 { node.count as f32 } else { TRAVERSAL_COST };
+            node.bbox().surface_area() * cost
+        }).sum();
+        sah_cost(self.nodes[0].bbox().surface_area(), area_cost)
+    }
+
+    /// Follows moving primitives to the time interval t0..t1, refitting unless that degrades
+    /// the tree past the rebuild threshold of its settings
+    pub fn update(&mut self, t0: f32, t1: f32) -> BVHUpdate {
+        self.refit(t0, t1);
+        if !self.settings.needs_rebuild(self.built_cost, self.sah_cost()) {
+            return BVHUpdate::Refit;
+        }
+
+        *self = FlatBVH::with_settings(&self.primitives, t0, t1, &self.settings).expect("No bounding box in FlatBVH rebuild");
+        BVHUpdate::Rebuild
+    }
+}
+
+impl Hittable for FlatBVH {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let origin = r.origin().v();
+        let dir = r.direction().v();
+        let inv_dir = [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]];
+
+        let mut stack = [0u32; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut current = 0;
+        let mut closest = t_max;
+        let mut result = None;
+        loop {
+            let node = &self.nodes[current];
+            if node.hit(origin, &inv_dir, t_min, closest) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    for primitive in &self.primitives[first..first + node.count as usize] {
+                        if let Some(hit) = primitive.hit(r, t_min, closest) {
+                            closest = hit.t;
+                            result = Some(hit);
+                        }
+                    }
+                } else {
+                    // The far child waits on the stack, its box is tested against the shrunk interval later
+                    let (near, far) = if inv_dir[node.axis as usize] < 0.0 {
+                        (node.offset, current as u32 + 1)
+                    } else {
+                        (current as u32 + 1, node.offset)
+                    };
+                    stack[stack_size] = far;
+                    stack_size += 1;
+                    current = near as usize;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                break;
+            }
+            stack_size -= 1;
+            current = stack[stack_size] as usize;
+        }
+
+        result
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        let origin = r.origin().v();
+        let dir = r.direction().v();
+        let inv_dir = [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]];
+
+        let mut stack = [0u32; MAX_DEPTH];
+        let mut stack_size = 0;
+        let mut current = 0;
+        loop {
+            let node = &self.nodes[current];
+            if node.hit(origin, &inv_dir, t_min, t_max) {
+                if node.count > 0 {
+                    let first = node.offset as usize;
+                    if self.primitives[first..first + node.count as usize].iter().any(|p| p.occluded(r, t_min, t_max)) {
+                        return true;
+                    }
+                } else {
+                    stack[stack_size] = node.offset;
+                    stack_size += 1;
+                    current += 1;
+                    continue;
+                }
+            }
+
+            if stack_size == 0 {
+                return false;
+            }
+            stack_size -= 1;
+            current = stack[stack_size] as usize;
+        }
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        Some(self.nodes[0].bbox())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::hittable::{HittableList, Lambertian, Material, MovingSphere, Sphere};
+    use crate::mesh::Triangle;
+    use crate::texture::ConstantTexture;
+    use crate::random;
+    use rand::Rng;
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new_fill(0.5)))))
+    }
+
+    fn random_point(size: f32) -> Vec3 {
+        let mut rng = random::rng();
+        size * Vec3::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5)
+    }
+
+    /// Spheres, moving spheres and triangles in a 20 unit cube, some triangles lie in
+    /// axis aligned planes so their boxes are flat but for the padding
+    pub(crate) fn test_objects(count: usize) -> Vec<Arc<dyn Hittable>> {
+        let material = material();
+        let mut rng = random::rng();
+        (0..count).map(|i| {
+            let center = random_point(20.0);
+            let size = 0.2 + rng.gen::<f32>();
+            match i % 4 {
+                0 => Arc::new(Sphere::new(center, size, material.clone())) as Arc<dyn Hittable>,
+                1 => Arc::new(MovingSphere::new(center, center + random_point(2.0), 0.0, 1.0, size, material.clone())),
+                2 => Arc::new(Triangle::new(center, center + random_point(3.0), center + random_point(3.0), material.clone())),
+                _ => Arc::new(Triangle::new(center, center + Vec3::new(size, 0.0, 0.0), center + Vec3::new(0.0, 0.0, size), material.clone())),
+            }
+        }).collect()
+    }
+
+    /// Random rays from inside the cube, every tenth one along an axis so that some
+    /// inverse direction components are infinite
+    pub(crate) fn test_rays(count: usize, t0: f32, t1: f32) -> Vec<Ray> {
+        let axes = [Vec3::right(), Vec3::up(), Vec3::forward(), -Vec3::right(), -Vec3::up(), -Vec3::forward()];
+        let mut rng = random::rng();
+        (0..count).map(|i| {
+            let direction = if i % 10 == 0 { axes[i / 10 % axes.len()] } else { random_point(2.0) };
+            Ray::new(random_point(24.0), direction, t0 + (t1 - t0) * rng.gen::<f32>())
+        }).collect()
+    }
+
+    /// Compares closest hits and occlusion of `bvh` with testing every object
+    pub(crate) fn assert_same_hits(label: &str, bvh: &dyn Hittable, objects: &[Arc<dyn Hittable>], rays: &[Ray]) {
+        let reference = HittableList { items: objects.to_vec() };
+        let mut hits = 0;
+        for (i, r) in rays.iter().enumerate() {
+            let expected = reference.hit(r, 0.001, f32::MAX).map(|hit| hit.t);
+            assert_eq!(bvh.hit(r, 0.001, f32::MAX).map(|hit| hit.t), expected, "{}: closest hit of ray {}", label, i);
+            hits += expected.is_some() as usize;
+            for &t_max in &[1.0, 5.0, f32::MAX] {
+                assert_eq!(bvh.occluded(r, 0.001, t_max), reference.occluded(r, 0.001, t_max), "{}: occlusion of ray {} up to {}", label, i, t_max);
+            }
+        }
+        // Otherwise the comparison proves little
+        assert!(hits > rays.len() / 10 && hits < rays.len(), "{}: {} of {} rays hit", label, hits, rays.len());
+    }
+
+    #[test]
+    fn hierarchies_match_testing_every_object() {
+        random::seed(1);
+        let objects = test_objects(500);
+        let rays = test_rays(2000, 0.0, 1.0);
+        assert_same_hits("BVHNode", &BVHNode::new(&objects, 0.0, 1.0).unwrap(), &objects, &rays);
+        assert_same_hits("FlatBVH", &FlatBVH::new(&objects, 0.0, 1.0).unwrap(), &objects, &rays);
+
+        // Large leaves and few bins take other paths through the builder
+        let settings = BVHSettings { max_leaf_size: 16, bins: 2, ..BVHSettings::default() };
+        assert_same_hits("BVHNode coarse", &BVHNode::with_settings(&objects, 0.0, 1.0, &settings).unwrap(), &objects, &rays);
+        assert_same_hits("FlatBVH coarse", &FlatBVH::with_settings(&objects, 0.0, 1.0, &settings).unwrap(), &objects, &rays);
+    }
+
+    /// Spheres which all move to where another one started between time 0 and 1
+    fn shuffling_spheres(count: usize) -> Vec<Arc<dyn Hittable>> {
+        let material = material();
+        let starts: Vec<Vec3> = (0..count).map(|_| random_point(20.0)).collect();
+        (0..count).map(|i| {
+            let end = starts[(i * 7 + 3) % count];
+            Arc::new(MovingSphere::new(starts[i], end, 0.0, 1.0, 0.5, material.clone())) as Arc<dyn Hittable>
+        }).collect()
+    }
+
+    #[test]
+    fn refitted_trees_match_a_fresh_build() {
+        random::seed(5);
+        let objects = shuffling_spheres(300);
+        let rays = test_rays(2000, 0.9, 1.0);
+        let mut tree = BVHNode::new(&objects, 0.0, 0.1).unwrap();
+        let mut flat = FlatBVH::new(&objects, 0.0, 0.1).unwrap();
+
+        // The stale bounds miss most of the moved spheres
+        let reference = HittableList { items: objects.clone() };
+        let stale = rays.iter().filter(|r| flat.hit(r, 0.001, f32::MAX).map(|hit| hit.t) != reference.hit(r, 0.001, f32::MAX).map(|hit| hit.t)).count();
+        assert!(stale > rays.len() / 10, "{} rays differ before the refit", stale);
+
+        tree.refit(0.9, 1.0);
+        flat.refit(0.9, 1.0);
+        assert_same_hits("BVHNode refit", &tree, &objects, &rays);
+        assert_same_hits("FlatBVH refit", &flat, &objects, &rays);
+        assert_same_hits("FlatBVH fresh", &FlatBVH::new(&objects, 0.9, 1.0).unwrap(), &objects, &rays);
+    }
+
+    #[test]
+    fn needs_rebuild_past_the_threshold() {
+        let settings = BVHSettings { rebuild_threshold: 1.5, ..BVHSettings::default() };
+        assert!(!settings.needs_rebuild(10.0, 10.0));
+        assert!(!settings.needs_rebuild(10.0, 15.0));
+        assert!(settings.needs_rebuild(10.0, 15.1));
+    }
+
+    #[test]
+    fn update_rebuilds_once_the_cost_grows_too_much() {
+        random::seed(6);
+        let objects = shuffling_spheres(300);
+        let mut bvh = FlatBVH::new(&objects, 0.0, 0.0).unwrap();
+        let built_cost = bvh.sah_cost();
+
+        // Barely moved, the topology still fits
+        assert_eq!(bvh.update(0.01, 0.01), BVHUpdate::Refit);
+        assert!(!bvh.settings.needs_rebuild(built_cost, bvh.sah_cost()));
+
+        // Every sphere ended up somewhere else, the old grouping makes the boxes overlap
+        let mut refitted = FlatBVH::new(&objects, 0.0, 0.0).unwrap();
+        refitted.refit(1.0, 1.0);
+        assert!(bvh.settings.needs_rebuild(built_cost, refitted.sah_cost()), "{} after {}", refitted.sah_cost(), built_cost);
+        assert_eq!(bvh.update(1.0, 1.0), BVHUpdate::Rebuild);
+        assert!(bvh.sah_cost() < refitted.sah_cost());
+
+        // The rebuilt tree is the new reference
+        assert_eq!(bvh.update(1.0, 1.0), BVHUpdate::Refit);
+        let rays = test_rays(1000, 1.0, 1.0);
+        assert_same_hits("FlatBVH rebuilt", &bvh, &objects, &rays);
+    }
+}
@@ -3,6 +3,25 @@ extern crate rand;
 use rand::Rng;
 use crate::vec3::{Vec3, random_in_unit_circle};
 use crate::ray::Ray;
+use crate::random;
+
+/// Camera description independent of the image resolution
+#[derive(Copy, Clone)]
+pub struct CameraSettings {
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub fov: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    pub shutter_time: f32,
+}
+
+impl CameraSettings {
+    pub fn build(&self, aspect: f32) -> Camera {
+        Camera::new(&self.look_from, &self.look_at, &self.up, self.fov, aspect, self.aperture, self.focus_dist, self.shutter_time)
+    }
+}
 
 pub struct Camera {
     origin: Vec3,
@@ -17,6 +36,8 @@ pub struct Camera {
 }
 
 impl Camera {
+    // Mirrors the constructor of the book
+    #[allow(clippy::too_many_arguments)]
     pub fn new(look_from: &Vec3, look_at: &Vec3, up: &Vec3, fov: f32, aspect: f32, aperture: f32, focus_dist: f32, shutter_time: f32) -> Camera {
         let theta = fov.to_radians();
         let half_height = (theta / 2.0).tan();
@@ -25,7 +46,7 @@ impl Camera {
         let u = up.cross(&w).normalized();
         let v = w.cross(&u);
         Camera {
-            lower_left: look_from - &(half_width * focus_dist * u) - half_height * focus_dist * v - focus_dist * w,
+            lower_left: *look_from - half_width * focus_dist * u - half_height * focus_dist * v - focus_dist * w,
             horizontal: 2.0 * half_width * focus_dist * u,
             vertical: 2.0 * half_height * focus_dist * v,
             origin: *look_from,
@@ -40,8 +61,8 @@ impl Camera {
     pub fn get_ray(&self, s: f32, t: f32, time_start: f32) -> Ray {
         let rd = self.lens_radius * random_in_unit_circle();
         let offset = self.u * rd.x() + self.v * rd.y();
-        let mut rng = rand::thread_rng();
+        let mut rng = random::rng();
         let ray_time = time_start + rng.gen::<f32>() * self.shutter_time;
-        Ray::new(self.origin + &offset, self.lower_left + s * self.horizontal + t * self.vertical - self.origin - offset, ray_time)
+        Ray::new(self.origin + offset, self.lower_left + s * self.horizontal + t * self.vertical - self.origin - offset, ray_time)
     }
 }
@@ -0,0 +1,400 @@
+use rusty_path::presets::PRESETS;
+use rusty_path::output::{ExrPixelType, PngBitDepth};
+use rusty_path::tonemap::{ToneMapOperator, ToneMapper};
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: rusty_path [OPTIONS] [SCENE_FILE]
+
+Renders SCENE_FILE, or a built-in preset when no file is given.
+
+Options:
+  -p, --preset NAME         Built-in scene to render instead of a scene file [default: random]
+  -r, --resolution WxH      Image resolution, overrides the scene (e.g. 1920x1080)
+  -s, --samples N           Samples per pixel, overrides the scene
+  -d, --max-depth N         Maximum number of bounces per path [default: 50]
+  -j, --threads N           Number of render threads [default: number of cores]
+      --seed N              Seed of the random generator, random when not given
+  -o, --output PATH         Output image, '-' writes to stdout [default: -]
+      --time-range START:END
+                            Renders the frames from START up to END seconds instead of one image,
+                            '#'s in the output name are replaced by the frame number [default output: frame_####.png]
+      --fps RATE            Frames per second of --time-range [default: 24]
+  -f, --format FORMAT       Output format (ppm, png, exr, hdr), deduced from the output extension when not given
+      --bit-depth BITS      Bits per channel of PNG output, 8 or 16 [default: 8]
+      --exr-type TYPE       Sample type of EXR output, half or float [default: half]
+  -t, --tonemap NAME        Tone mapping of PPM and PNG output: clamp, reinhard, reinhard-extended,
+                            aces, hable or agx [default: clamp]
+      --white-point L       Luminance mapped to white by reinhard-extended [default: 4]
+  -e, --exposure EV         Exposure of PPM and PNG output in stops [default: 0]
+      --help                Prints this help
+";
+
+/// Every option besides --help takes a value
+const OPTIONS: &[&str] = &[
+    "-p", "--preset", "-r", "--resolution", "-s", "--samples", "-d", "--max-depth", "-j", "--threads", "--seed",
+    "-o", "--output", "--time-range", "--fps", "-f", "--format", "--bit-depth", "--exr-type", "-t", "--tonemap",
+    "--white-point", "-e", "--exposure",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+    Ppm,
+    Png,
+    Exr,
+    Hdr,
+}
+
+impl OutputFormat {
+    const NAMES: &'static [(&'static str, OutputFormat)] = &[
+        ("ppm", OutputFormat::Ppm),
+        ("png", OutputFormat::Png),
+        ("exr", OutputFormat::Exr),
+        ("hdr", OutputFormat::Hdr),
+    ];
+
+    fn from_name(name: &str) -> Option<OutputFormat> {
+        let name = name.to_ascii_lowercase();
+        OutputFormat::NAMES.iter().find(|(n, _)| *n == name).map(|(_, f)| *f)
+    }
+
+    fn from_path(path: &Path) -> Option<OutputFormat> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(OutputFormat::from_name)
+    }
+
+    fn name(self) -> &'static str {
+        OutputFormat::NAMES.iter().find(|(_, f)| *f == self).map(|(n, _)| *n).unwrap()
+    }
+
+    fn names() -> String {
+        OutputFormat::NAMES.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(", ")
+    }
+}
+
+/// Frames of an animation, the first one starts at `start`
+#[derive(Copy, Clone, Debug)]
+pub struct Sequence {
+    pub start: f32,
+    pub end: f32,
+    pub fps: f32,
+}
+
+impl Sequence {
+    /// Every frame starting before `end`
+    pub fn frame_count(&self) -> usize {
+        (((self.end - self.start) * self.fps).ceil() as usize).max(1)
+    }
+
+    /// Time the shutter opens, `frame` counts from 0
+    pub fn frame_time(&self, frame: usize) -> f32 {
+        self.start + frame as f32 / self.fps
+    }
+}
+
+pub enum SceneSource {
+    File(PathBuf),
+    Preset(String),
+}
+
+pub struct Options {
+    pub scene: SceneSource,
+    pub resolution: Option<(usize, usize)>,
+    pub samples: Option<u32>,
+    pub max_depth: u32,
+    pub threads: Option<usize>,
+    pub seed: Option<u64>,
+    /// None writes to stdout
+    pub output: Option<PathBuf>,
+    /// Renders frames instead of a single image, `output` is the name pattern then
+    pub sequence: Option<Sequence>,
+    pub format: OutputFormat,
+    pub bit_depth: PngBitDepth,
+    pub exr_type: ExrPixelType,
+    pub tone_map: ToneMapper,
+}
+
+pub enum Command {
+    Help,
+    Render(Options),
+}
+
+fn parse_positive<T: std::str::FromStr + PartialOrd + Default>(option: &str, value: &str) -> Result<T, String> {
+    match value.parse::<T>() {
+        Ok(v) if v > T::default() => Ok(v),
+        _ => Err(format!("{} expects a positive integer, got '{}'", option, value)),
+    }
+}
+
+fn parse_time_range(value: &str) -> Result<(f32, f32), String> {
+    let times: Vec<Option<f32>> = value.split(':').map(|time| time.parse::<f32>().ok().filter(|t| t.is_finite())).collect();
+    match times[..] {
+        [Some(start), Some(end)] if end > start => Ok((start, end)),
+        _ => Err(format!("--time-range expects START:END in seconds with END after START, got '{}'", value)),
+    }
+}
+
+fn parse_resolution(value: &str) -> Result<(usize, usize), String> {
+    let parts: Vec<&str> = value.split(['x', 'X']).collect();
+    if parts.len() != 2 {
+        return Err(format!("--resolution expects WIDTHxHEIGHT, got '{}'", value));
+    }
+    Ok((parse_positive("--resolution", parts[0])?, parse_positive("--resolution", parts[1])?))
+}
+
+/// Parses the arguments without the program name
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut scene_file: Option<PathBuf> = None;
+    let mut preset: Option<String> = None;
+    let mut resolution = None;
+    let mut samples = None;
+    let mut max_depth = 50;
+    let mut threads = None;
+    let mut seed = None;
+    let mut output: Option<String> = None;
+    let mut time_range = None;
+    let mut fps = None;
+    let mut format = None;
+    let mut bit_depth = None;
+    let mut exr_type = None;
+    let mut tonemap: Option<String> = None;
+    let mut white_point = None;
+    let mut exposure = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if scene_file.is_some() {
+                return Err(format!("unexpected argument '{}', only one scene file can be rendered", arg));
+            }
+            scene_file = Some(PathBuf::from(arg));
+            continue;
+        }
+
+        // Both `--option value` and `--option=value` are accepted
+        let (option, inline_value) = match arg.find('=') {
+            Some(i) if arg.starts_with("--") => (arg[..i].to_string(), Some(arg[i + 1..].to_string())),
+            _ => (arg.clone(), None),
+        };
+        if option == "--help" {
+            return Ok(Command::Help);
+        }
+
+        // Unknown options are reported as such even when no value follows them
+        if !OPTIONS.contains(&option.as_str()) {
+            return Err(format!("unknown option '{}'", option));
+        }
+        let value = match inline_value {
+            Some(value) => value,
+            None => args.next().ok_or_else(|| format!("{} expects a value", option))?,
+        };
+        match option.as_str() {
+            "-p" | "--preset" => preset = Some(value),
+            "-r" | "--resolution" => resolution = Some(parse_resolution(&value)?),
+            "-s" | "--samples" => samples = Some(parse_positive(&option, &value)?),
+            "-d" | "--max-depth" => max_depth = parse_positive(&option, &value)?,
+            "-j" | "--threads" => threads = Some(parse_positive(&option, &value)?),
+            "--seed" => seed = Some(value.parse::<u64>().map_err(|_| format!("--seed expects a non-negative integer, got '{}'", value))?),
+            "-o" | "--output" => output = Some(value),
+            "--time-range" => time_range = Some(parse_time_range(&value)?),
+            "--fps" => fps = Some(match value.parse::<f32>() {
+                Ok(v) if v > 0.0 && v.is_finite() => v,
+                _ => return Err(format!("--fps expects a positive number, got '{}'", value)),
+            }),
+            "-f" | "--format" => format = Some(OutputFormat::from_name(&value)
+                .ok_or_else(|| format!("unknown format '{}', supported formats are {}", value, OutputFormat::names()))?),
+            "--bit-depth" => bit_depth = Some(match value.as_str() {
+                "8" => PngBitDepth::Eight,
+                "16" => PngBitDepth::Sixteen,
+                _ => return Err(format!("--bit-depth expects 8 or 16, got '{}'", value)),
+            }),
+            "--exr-type" => exr_type = Some(match value.as_str() {
+                "half" => ExrPixelType::Half,
+                "float" => ExrPixelType::Float,
+                _ => return Err(format!("--exr-type expects half or float, got '{}'", value)),
+            }),
+            "-t" | "--tonemap" => tonemap = Some(value),
+            "--white-point" => white_point = Some(match value.parse::<f32>() {
+                Ok(v) if v > 0.0 => v,
+                _ => return Err(format!("--white-point expects a positive number, got '{}'", value)),
+            }),
+            "-e" | "--exposure" => exposure = Some(match value.parse::<f32>() {
+                Ok(v) if v.is_finite() => v,
+                _ => return Err(format!("--exposure expects a number, got '{}'", value)),
+            }),
+            _ => unreachable!("option '{}' is missing from the match", option),
+        }
+    }
+
+    let scene = match (scene_file, preset) {
+        (Some(_), Some(_)) => return Err("a scene file and --preset cannot be used together".to_string()),
+        (Some(file), None) => SceneSource::File(file),
+        (None, Some(name)) => {
+            if !PRESETS.contains(&name.as_str()) {
+                return Err(format!("unknown preset '{}', available presets are {}", name, PRESETS.join(", ")));
+            }
+            SceneSource::Preset(name)
+        },
+        (None, None) => SceneSource::Preset(PRESETS[0].to_string()),
+    };
+
+    let sequence = match (time_range, fps) {
+        (Some((start, end)), fps) => Some(Sequence { start, end, fps: fps.unwrap_or(24.0) }),
+        (None, Some(_)) => return Err("--fps only applies to --time-range".to_string()),
+        (None, None) => None,
+    };
+    let output = match (output.as_deref(), sequence) {
+        (Some("-"), Some(_)) => return Err("frames of --time-range cannot be written to stdout".to_string()),
+        (Some("-"), None) | (None, None) => None,
+        (None, Some(_)) => Some(PathBuf::from("frame_####.png")),
+        (Some(path), _) => Some(PathBuf::from(path)),
+    };
+
+    let format = match (format, output.as_deref().map(OutputFormat::from_path)) {
+        (Some(format), None) => format,
+        (None, None) => OutputFormat::Ppm,
+        (Some(format), Some(Some(from_path))) if format != from_path => {
+            return Err(format!("--format {} does not match the output file extension which means {}", format.name(), from_path.name()));
+        },
+        (Some(format), Some(_)) => format,
+        (None, Some(Some(from_path))) => from_path,
+        (None, Some(None)) => {
+            return Err(format!("cannot deduce the format from the output file name, use --format with one of {}", OutputFormat::names()));
+        },
+    };
+
+    if bit_depth.is_some() && format != OutputFormat::Png {
+        return Err(format!("--bit-depth only applies to png output, not {}", format.name()));
+    }
+    if exr_type.is_some() && format != OutputFormat::Exr {
+        return Err(format!("--exr-type only applies to exr output, not {}", format.name()));
+    }
+
+    let display_referred = format == OutputFormat::Ppm || format == OutputFormat::Png;
+    if (tonemap.is_some() || exposure.is_some()) && !display_referred {
+        return Err(format!("tone mapping only applies to ppm and png output, {} keeps linear values", format.name()));
+    }
+    let operator = match tonemap.as_deref().unwrap_or("clamp") {
+        "clamp" => ToneMapOperator::Clamp,
+        "reinhard" => ToneMapOperator::Reinhard,
+        "reinhard-extended" => ToneMapOperator::ReinhardExtended { white: white_point.unwrap_or(4.0) },
+        "aces" => ToneMapOperator::Aces,
+        "hable" => ToneMapOperator::Hable,
+        "agx" => ToneMapOperator::AgX,
+        name => return Err(format!("unknown tone map '{}', expected clamp, reinhard, reinhard-extended, aces, hable or agx", name)),
+    };
+    if white_point.is_some() && !matches!(operator, ToneMapOperator::ReinhardExtended { .. }) {
+        return Err("--white-point only applies to --tonemap reinhard-extended".to_string());
+    }
+
+    Ok(Command::Render(Options {
+        scene,
+        resolution,
+        samples,
+        max_depth,
+        threads,
+        seed,
+        output,
+        sequence,
+        format,
+        bit_depth: bit_depth.unwrap_or(PngBitDepth::Eight),
+        exr_type: exr_type.unwrap_or(ExrPixelType::Half),
+        tone_map: ToneMapper::new(operator, exposure.unwrap_or(0.0))
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(args.split_whitespace().map(str::to_string))
+    }
+
+    fn options(args: &str) -> Options {
+        match parse(args) {
+            Ok(Command::Render(options)) => options,
+            Ok(Command::Help) => panic!("'{}' asked for help", args),
+            Err(error) => panic!("'{}' failed: {}", args, error),
+        }
+    }
+
+    fn error(args: &str) -> String {
+        parse(args).err().unwrap_or_else(|| panic!("'{}' was accepted", args))
+    }
+
+    #[test]
+    fn defaults_to_the_first_preset_on_stdout() {
+        let options = options("");
+        assert!(matches!(options.scene, SceneSource::Preset(ref name) if name == PRESETS[0]));
+        assert!(options.output.is_none());
+        assert!(options.sequence.is_none());
+        assert_eq!(options.format, OutputFormat::Ppm);
+        assert_eq!(options.max_depth, 50);
+    }
+
+    #[test]
+    fn accepts_inline_values_and_deduces_the_format() {
+        let options = options("scene.txt --resolution=64x32 -s 8 --output=out.png --bit-depth 16");
+        assert!(matches!(options.scene, SceneSource::File(ref path) if path == Path::new("scene.txt")));
+        assert_eq!(options.resolution, Some((64, 32)));
+        assert_eq!(options.samples, Some(8));
+        assert_eq!(options.format, OutputFormat::Png);
+        assert_eq!(options.bit_depth, PngBitDepth::Sixteen);
+    }
+
+    #[test]
+    fn renders_sequences_to_numbered_files() {
+        let options = options("--time-range 0:2 --fps 10");
+        let sequence = options.sequence.unwrap();
+        assert_eq!(sequence.frame_count(), 20);
+        assert_eq!(options.output.as_deref(), Some(Path::new("frame_####.png")));
+        assert_eq!(options.format, OutputFormat::Png);
+    }
+
+    #[test]
+    fn rejects_invalid_combinations() {
+        assert_eq!(error("--fps 30"), "--fps only applies to --time-range");
+        assert_eq!(error("--time-range 0:1 -o -"), "frames of --time-range cannot be written to stdout");
+        assert_eq!(error("scene.txt --preset cornell"), "a scene file and --preset cannot be used together");
+        assert!(error("-o out.png --format exr").starts_with("--format exr does not match"));
+        assert!(error("-o out.tiff").starts_with("cannot deduce the format"));
+        assert!(error("-o out.exr --bit-depth 16").starts_with("--bit-depth only applies to png output"));
+        assert!(error("-o out.png --exr-type float").starts_with("--exr-type only applies to exr output"));
+        assert!(error("-o out.exr --tonemap aces").starts_with("tone mapping only applies to ppm and png output"));
+        assert!(error("-o out.hdr --exposure 1").starts_with("tone mapping only applies to ppm and png output"));
+        assert_eq!(error("--tonemap aces --white-point 2"), "--white-point only applies to --tonemap reinhard-extended");
+        assert_eq!(error("--white-point 2"), "--white-point only applies to --tonemap reinhard-extended");
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(error("--preset teapot").starts_with("unknown preset 'teapot'"));
+        assert!(error("--format tiff").starts_with("unknown format 'tiff'"));
+        assert!(error("--tonemap filmic").starts_with("unknown tone map 'filmic'"));
+        assert!(error("--resolution 640").starts_with("--resolution expects WIDTHxHEIGHT"));
+        assert!(error("--resolution 0x480").starts_with("--resolution expects a positive integer"));
+        assert!(error("--time-range 2:1").starts_with("--time-range expects START:END"));
+        assert!(error("--samples -1").starts_with("--samples expects a positive integer"));
+        assert_eq!(error("--samples"), "--samples expects a value");
+        assert_eq!(error("--verbose 1"), "unknown option '--verbose'");
+        assert_eq!(error("--verbose"), "unknown option '--verbose'");
+        assert_eq!(error("scene.txt -v"), "unknown option '-v'");
+        assert_eq!(error("--verbose=1"), "unknown option '--verbose'");
+        assert!(error("a.txt b.txt").starts_with("unexpected argument 'b.txt'"));
+    }
+
+    #[test]
+    fn every_option_is_handled() {
+        for option in OPTIONS {
+            // Some values are invalid for the option, but none should reach the end of the match
+            let _ = parse(&format!("{} 1", option));
+        }
+    }
+
+    #[test]
+    fn help_wins_over_other_arguments() {
+        assert!(matches!(parse("scene.txt --help --samples"), Ok(Command::Help)));
+    }
+}
@@ -1,24 +1,42 @@
 use crate::vec3::*;
 use crate::ray::Ray;
+use crate::random;
 use crate::aabb::AABB;
 use crate::texture::*;
 use std::vec::Vec;
 use rand::Rng;
 use std::sync::Arc;
-use std::cmp;
+
+pub use crate::bvh::{BVHNode, FlatBVH};
 
 
 pub trait Material : Send + Sync {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vec3)>;
+
+    /// Radiance emitted from the hit point towards the ray origin
+    fn emitted(&self, _ray: &Ray, _hit: &HitRecord) -> Vec3 {
+        Vec3::zero()
+    }
+
+    /// BSDF times cosine for light coming from `direction` and the pdf of `scatter` generating it
+    /// None for specular materials which cannot be combined with light sampling
+    fn evaluate(&self, _ray: &Ray, _hit: &HitRecord, _direction: &Vec3) -> Option<(Vec3, f32)> {
+        None
+    }
+
+    /// Objects with emissive materials are sampled directly as lights
+    fn is_emissive(&self) -> bool {
+        false
+    }
 }
 
 
 pub struct Lambertian {
-    albedo: Box<dyn Texture>,
+    albedo: Arc<dyn Texture>,
 }
 
 impl Lambertian {
-    pub fn new(albedo: Box<dyn Texture>) -> Lambertian {
+    pub fn new(albedo: Arc<dyn Texture>) -> Lambertian {
         Lambertian {
             albedo
         }
@@ -27,11 +45,22 @@ impl Lambertian {
 
 impl Material for Lambertian {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vec3)> {
-        let target = hit.p + hit.normal + random_in_unit_sphere();
-        let scattered = Ray::new(hit.p, target - hit.p, ray.time());
-        let attenuation = self.albedo.value(0.0, 0.0, hit.p);
+        // Cosine distributed, so the attenuation is just the albedo
+        let normal = hit.facing_normal();
+        let mut direction = normal + random_unit_vector();
+        if direction.length_sqr() < 1e-8 {
+            direction = normal;
+        }
+        let scattered = Ray::new(hit.p, direction, ray.time());
+        let attenuation = self.albedo.value(hit.uv.0, hit.uv.1, hit.p);
         Some((scattered, attenuation))
     }
+
+    fn evaluate(&self, _ray: &Ray, hit: &HitRecord, direction: &Vec3) -> Option<(Vec3, f32)> {
+        let cosine = hit.facing_normal().dot(&direction.normalized()).max(0.0);
+        let pdf = cosine / std::f32::consts::PI;
+        Some((pdf * self.albedo.value(hit.uv.0, hit.uv.1, hit.p), pdf))
+    }
 }
 
 
@@ -51,8 +80,9 @@ impl Metal {
 
 impl Material for Metal {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vec3)> {
-        let scattered = Ray::new(hit.p, ray.direction().normalized().reflect(&hit.normal) + self.roughness * random_in_unit_sphere(), ray.time());
-        if scattered.direction().dot(&hit.normal) > 0.0 {
+        let normal = hit.facing_normal();
+        let scattered = Ray::new(hit.p, ray.direction().normalized().reflect(&normal) + self.roughness * random_in_unit_sphere(), ray.time());
+        if scattered.direction().dot(&normal) > 0.0 {
             Some((
                 scattered,
                 self.albedo
@@ -78,19 +108,19 @@ impl Dielectric {
 
 impl Material for Dielectric {
     fn scatter(&self, ray: &Ray, hit: &HitRecord) -> Option<(Ray, Vec3)> {
-        let (outward_normal, ni_over_nt, cosine) = if ray.direction().dot(&hit.normal) > 0.0 {
-            (-hit.normal, 
-            self.ref_idx,
-            self.ref_idx * ray.direction().dot(&hit.normal) / ray.direction().length())
-        } else {
-            (hit.normal, 
+        let (outward_normal, ni_over_nt, cosine) = if hit.front_face {
+            (hit.normal,
             1.0 / self.ref_idx,
             -ray.direction().dot(&hit.normal) / ray.direction().length())
+        } else {
+            (-hit.normal,
+            self.ref_idx,
+            self.ref_idx * ray.direction().dot(&hit.normal) / ray.direction().length())
         };
 
         let attuneation = Vec3::new(1.0, 1.0, 1.0);
         if let Some(refracted) = ray.direction().refract(&outward_normal, ni_over_nt) {
-            if rand::thread_rng().gen::<f32>() >= schlick(cosine, self.ref_idx) {
+            if random::rng().gen::<f32>() >= schlick(cosine, self.ref_idx) {
                 return Some((Ray::new(hit.p, refracted, ray.time()), attuneation));
             }
         }
@@ -100,16 +130,149 @@ impl Material for Dielectric {
 }
 
 
+pub struct DiffuseLight {
+    emit: Arc<dyn Texture>,
+    two_sided: bool,
+}
+
+impl DiffuseLight {
+    /// One-sided lights only emit to the side the normal points to
+    pub fn new(emit: Arc<dyn Texture>, two_sided: bool) -> DiffuseLight {
+        DiffuseLight {
+            emit,
+            two_sided
+        }
+    }
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _ray: &Ray, _hit: &HitRecord) -> Option<(Ray, Vec3)> {
+        None
+    }
+
+    fn emitted(&self, _ray: &Ray, hit: &HitRecord) -> Vec3 {
+        if self.two_sided || hit.front_face {
+            self.emit.value(hit.uv.0, hit.uv.1, hit.p)
+        } else {
+            Vec3::zero()
+        }
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+
 pub struct HitRecord {
     pub material: Arc<dyn Material>,
     pub p: Vec3,
+    /// Normal used for shading, interpolated on meshes, on the same side as `geometric_normal`
     pub normal: Vec3,
-    pub t: f32
+    /// Normal of the actual surface, pointing out of closed objects
+    pub geometric_normal: Vec3,
+    /// Whether the ray arrives from the side `geometric_normal` points to
+    pub front_face: bool,
+    pub t: f32,
+    /// Surface coordinates for texturing
+    pub uv: (f32, f32),
+    /// Derivatives of `p` along u and v
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+}
+
+impl HitRecord {
+    /// Shading normal turned to the side the ray came from, so both sides of open surfaces reflect
+    pub fn facing_normal(&self) -> Vec3 {
+        if self.front_face { self.normal } else { -self.normal }
+    }
 }
 
 pub trait Hittable : Sync + Send {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord>;
     fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB>;
+
+    /// Solid angle density of `sample_direction` generating `direction` from `origin`
+    fn pdf_value(&self, _origin: &Vec3, _direction: &Vec3, _time: f32) -> f32 {
+        0.0
+    }
+
+    /// Random direction from `origin` towards the object for sampling it as a light
+    /// None when the object cannot be sampled from `origin`
+    fn sample_direction(&self, _origin: &Vec3, _time: f32) -> Option<Vec3> {
+        None
+    }
+
+    /// Whether anything is hit between t_min and t_max, can stop at the first hit found
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.hit(r, t_min, t_max).is_some()
+    }
+}
+
+/// Closest ray parameter in t_min..t_max where the ray enters or leaves the sphere
+fn sphere_intersect(center: &Vec3, radius: f32, r: &Ray, t_min: f32, t_max: f32) -> Option<f32> {
+    let oc = r.origin() - center;
+    let a = r.direction().dot(r.direction());
+    let b = oc.dot(r.direction());
+    let c = oc.dot(&oc) - sqr(radius);
+    // b² - ac written with the distance of the center from the ray, for small spheres far away
+    // b² and ac cancel each other out and the squared radius gets lost in rounding
+    let off_axis = oc - (b / a) * r.direction();
+    let discriminant = a * (sqr(radius) - off_axis.dot(&off_axis));
+    if discriminant > 0.0 {
+        // The root on the side of -b is exact, the other one follows from their product c / a
+        let q = -b - b.signum() * discriminant.sqrt();
+        let (near, far) = (q / a, c / q);
+        let (near, far) = if near <= far { (near, far) } else { (far, near) };
+        if near < t_max && near > t_min {
+            return Some(near);
+        }
+        if far < t_max && far > t_min {
+            return Some(far);
+        }
+    }
+
+    None
+}
+
+/// Outward normal, UV and tangents of a point on a sphere.
+/// u goes around the y axis starting at -x and v from the bottom pole to the top one.
+fn sphere_surface(center: &Vec3, radius: f32, point: &Vec3) -> (Vec3, (f32, f32), Vec3, Vec3) {
+    let normal = (point - center) / radius;
+    let theta = clamp(-normal.y(), -1.0, 1.0).acos();
+    let phi = (-normal.z()).atan2(normal.x()) + std::f32::consts::PI;
+    let uv = (phi / (2.0 * std::f32::consts::PI), theta / std::f32::consts::PI);
+
+    // Written with phi instead of dividing by sin(theta) so the poles stay finite
+    let dpdu = 2.0 * std::f32::consts::PI * radius * Vec3::new(normal.z(), 0.0, -normal.x());
+    let dpdv = std::f32::consts::PI * radius * Vec3::new(normal.y() * phi.cos(), theta.sin(), -normal.y() * phi.sin());
+    (normal, uv, dpdu, dpdv)
+}
+
+/// `1 - cos` of the half angle of the cone the sphere subtends from `origin`, None from inside.
+/// Written as `sin² / (1 + cos)` since `1 - cos` itself rounds to zero for lights a few thousand radii away
+fn sphere_cone(center: &Vec3, radius: f32, origin: &Vec3) -> Option<f32> {
+    let sin_theta_max_sqr = sqr(radius) / (center - origin).length_sqr();
+    if sin_theta_max_sqr >= 1.0 {
+        return None;
+    }
+    let cos_theta_max = (1.0 - sin_theta_max_sqr).sqrt();
+    Some(sin_theta_max_sqr / (1.0 + cos_theta_max))
+}
+
+/// Solid angle density of sampling a sphere uniformly inside the cone it subtends,
+/// zero from inside where `sphere_sample_direction` does not sample at all
+fn sphere_pdf_value(center: &Vec3, radius: f32, origin: &Vec3) -> f32 {
+    match sphere_cone(center, radius, origin) {
+        Some(one_minus_cos_theta_max) => 1.0 / (2.0 * std::f32::consts::PI * one_minus_cos_theta_max),
+        None => 0.0,
+    }
+}
+
+/// From inside the whole sphere is visible and the cone degenerates
+fn sphere_sample_direction(center: &Vec3, radius: f32, origin: &Vec3) -> Option<Vec3> {
+    let one_minus_cos_theta_max = sphere_cone(center, radius, origin)?;
+    Some(random_in_cone(&(center - origin).normalized(), one_minus_cos_theta_max))
 }
 
 pub struct Sphere {
@@ -130,36 +293,24 @@ impl Sphere {
 
 impl Hittable for Sphere {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = r.origin() - &self.center;
-        let a = r.direction().dot(r.direction());
-        let b = oc.dot(r.direction());
-        let c = oc.dot(&oc) - sqr(self.radius);
-        let discriminant = sqr(b) - a * c;
-        if discriminant > 0.0 {
-            let temp = (-b - discriminant.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = r.point_at(temp);
-                return Some(HitRecord {
-                    material: self.material.clone(),
-                    p: point,
-                    normal: (point - self.center) / self.radius,
-                    t: temp
-                });
-            }
-
-            let temp = (-b + discriminant.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = r.point_at(temp);
-                return Some(HitRecord {
-                    material: self.material.clone(),
-                    p: point,
-                    normal: (point - self.center) / self.radius,
-                    t: temp
-                });
-            }
-        }
+        let t = sphere_intersect(&self.center, self.radius, r, t_min, t_max)?;
+        let point = r.point_at(t);
+        let (normal, uv, dpdu, dpdv) = sphere_surface(&self.center, self.radius, &point);
+        Some(HitRecord {
+            material: self.material.clone(),
+            p: point,
+            normal,
+            geometric_normal: normal,
+            front_face: r.direction().dot(&normal) < 0.0,
+            t,
+            uv,
+            dpdu,
+            dpdv
+        })
+    }
 
-        None
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        sphere_intersect(&self.center, self.radius, r, t_min, t_max).is_some()
     }
 
     fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
@@ -168,6 +319,17 @@ impl Hittable for Sphere {
             self.center + Vec3::new_fill(self.radius),
         ))
     }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        match self.hit(&Ray::new(*origin, *direction, time), 0.001, f32::MAX) {
+            Some(_) => sphere_pdf_value(&self.center, self.radius, origin),
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, _time: f32) -> Option<Vec3> {
+        sphere_sample_direction(&self.center, self.radius, origin)
+    }
 }
 
 pub struct MovingSphere {
@@ -200,36 +362,25 @@ impl MovingSphere {
 
 impl Hittable for MovingSphere {
     fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        let oc = r.origin() - &self.center(r.time());
-        let a = r.direction().dot(r.direction());
-        let b = oc.dot(r.direction());
-        let c = oc.dot(&oc) - sqr(self.radius);
-        let discriminant = sqr(b) - a * c;
-        if discriminant > 0.0 {
-            let temp = (-b - discriminant.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = r.point_at(temp);
-                return Some(HitRecord {
-                    material: self.material.clone(),
-                    p: point,
-                    normal: (point - self.center(r.time())) / self.radius,
-                    t: temp
-                });
-            }
-
-            let temp = (-b + discriminant.sqrt()) / a;
-            if temp < t_max && temp > t_min {
-                let point = r.point_at(temp);
-                return Some(HitRecord {
-                    material: self.material.clone(),
-                    p: point,
-                    normal: (point - self.center(r.time())) / self.radius,
-                    t: temp
-                });
-            }
-        }
+        let center = self.center(r.time());
+        let t = sphere_intersect(&center, self.radius, r, t_min, t_max)?;
+        let point = r.point_at(t);
+        let (normal, uv, dpdu, dpdv) = sphere_surface(&center, self.radius, &point);
+        Some(HitRecord {
+            material: self.material.clone(),
+            p: point,
+            normal,
+            geometric_normal: normal,
+            front_face: r.direction().dot(&normal) < 0.0,
+            t,
+            uv,
+            dpdu,
+            dpdv
+        })
+    }
 
-        None
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        sphere_intersect(&self.center(r.time()), self.radius, r, t_min, t_max).is_some()
     }
 
     fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
@@ -247,6 +398,112 @@ impl Hittable for MovingSphere {
         b0.add(&b1);
         Some(b0)
     }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        match self.hit(&Ray::new(*origin, *direction, time), 0.001, f32::MAX) {
+            Some(_) => sphere_pdf_value(&self.center(time), self.radius, origin),
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        sphere_sample_direction(&self.center(time), self.radius, origin)
+    }
+}
+
+/// Infinite plane through `point`, it has no bounding box so BVHs cannot hold it.
+/// UVs are distances from `point` along two perpendicular directions in the plane.
+pub struct Plane {
+    point: Vec3,
+    normal: Vec3,
+    dpdu: Vec3,
+    dpdv: Vec3,
+    material: Arc<dyn Material>,
+}
+
+impl Plane {
+    pub fn new(point: Vec3, normal: Vec3, material: Arc<dyn Material>) -> Plane {
+        let normal = normal.normalized();
+        // Swapped so that dpdu × dpdv is the normal
+        let (dpdv, dpdu) = orthonormal_basis(&normal);
+        Plane {
+            point,
+            normal,
+            dpdu,
+            dpdv,
+            material
+        }
+    }
+}
+
+impl Hittable for Plane {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let denominator = self.normal.dot(r.direction());
+        if denominator == 0.0 {
+            return None;
+        }
+        let t = self.normal.dot(&(self.point - *r.origin())) / denominator;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let p = r.point_at(t);
+        let planar = p - self.point;
+        Some(HitRecord {
+            material: self.material.clone(),
+            p,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            front_face: denominator < 0.0,
+            t,
+            uv: (planar.dot(&self.dpdu), planar.dot(&self.dpdv)),
+            dpdu: self.dpdu,
+            dpdv: self.dpdv
+        })
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        None
+    }
+}
+
+/// Turns a surface inside out, for one-sided lights and materials facing the other way
+pub struct FlipNormals {
+    object: Arc<dyn Hittable>,
+}
+
+impl FlipNormals {
+    pub fn new(object: Arc<dyn Hittable>) -> FlipNormals {
+        FlipNormals {
+            object
+        }
+    }
+}
+
+impl Hittable for FlipNormals {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut hit = self.object.hit(r, t_min, t_max)?;
+        hit.normal = -hit.normal;
+        hit.geometric_normal = -hit.geometric_normal;
+        hit.front_face = !hit.front_face;
+        Some(hit)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.object.occluded(r, t_min, t_max)
+    }
+
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.object.bouding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.object.pdf_value(origin, direction, time)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        self.object.sample_direction(origin, time)
+    }
 }
 
 pub struct HittableList {
@@ -258,19 +515,19 @@ impl Hittable for HittableList {
         let mut result = None;
         let mut closest = t_max;
         for item in &self.items {
-            let current = item.hit(r, t_min, closest);
-            match &current {
-                Some(current_hit) => {
-                    closest = current_hit.t;
-                    result = current;
-                },
-                _ => ()
+            if let Some(current_hit) = item.hit(r, t_min, closest) {
+                closest = current_hit.t;
+                result = Some(current_hit);
             }
         }
 
         result
     }
 
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.items.iter().any(|item| item.occluded(r, t_min, t_max))
+    }
+
     fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
         let mut result = AABB::new_empty();
 
@@ -284,132 +541,76 @@ impl Hittable for HittableList {
 
         Some(result)
     }
-}
-
-pub struct BVHNode {
-    left: Arc<dyn Hittable>,
-    right: Arc<dyn Hittable>,
-    bbox: AABB,
-}
-
-trait Axis {
-    fn value() -> usize;
-}
-struct X {}
-impl Axis for X {
-    fn value() -> usize {0}
-}
-struct Y {}
-impl Axis for Y {
-    fn value() -> usize {1}
-}
-struct Z {}
-impl Axis for Z {
-    fn value() -> usize {2}
-}
 
-fn axis_sort<TAxis: Axis>(a: &Arc<dyn Hittable>, b: &Arc<dyn Hittable>) -> cmp::Ordering {
-    if let (Some(abb), Some(bbb)) = (a.bouding_box(0.0, 0.0), b.bouding_box(0.0, 0.0)) {
-        if abb.min().v()[TAxis::value()] - bbb.min().v()[TAxis::value()] < 0.0 {
-            return cmp::Ordering::Less;
-        } else {
-            return cmp::Ordering::Greater;
+    /// Items are picked uniformly so the density is the average
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        if self.items.is_empty() {
+            return 0.0;
         }
+        let sum: f32 = self.items.iter().map(|item| item.pdf_value(origin, direction, time)).sum();
+        sum / self.items.len() as f32
     }
 
-    panic!("No bounding box int BVHNode constructor");
-}
-
-impl BVHNode {
-    pub fn new (objects: &mut [Arc<dyn Hittable>], t0: f32, t1: f32) -> BVHNode {
-        let axis = rand::thread_rng().gen_range(0, 3);
-        
-        /*eprintln!("++++++++++++");
-
-        for o in objects.iter() {
-            if let Some(bb) = o.bouding_box(0.0, 0.0) {
-                eprintln!("{:?}", bb.min());
-            }
-        }*/
-
-        // Sort by random axis to split in two halves
-        match axis {
-            0 => objects.sort_by(axis_sort::<X>),
-            1 => objects.sort_by(axis_sort::<Y>),
-            2 => objects.sort_by(axis_sort::<Z>),
-            _ => panic!("Invalid axis number"),
-        };
-
-        /*eprintln!("------------");
-
-        for o in objects.iter() {
-            if let Some(bb) = o.bouding_box(0.0, 0.0) {
-                eprintln!("{:?}", bb.min());
-            }
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        if self.items.is_empty() {
+            return None;
         }
+        let index = random::rng().gen_range(0, self.items.len());
+        self.items[index].sample_direction(origin, time)
+    }
+}
 
-        eprintln!("____________");*/
-
-        // Split in two halves
-        let (left, right) = if objects.len() == 2 {
-            (objects[0].clone(), objects[1].clone())
-        } else {
-            // TODO(aldarrion): Is all this necessary? Could we exploit the division maybe?
-            let first_half = objects.len() / 2;
-            let second_half = objects.len() - objects.len() / 2;
-            let l = if first_half == 1 {
-                objects[0].clone()
-            } else {
-                Arc::new(BVHNode::new(&mut objects[0..first_half], t0, t1))
-            };
-            let r = if second_half == 1 {
-                objects[1].clone()
-            } else {
-                Arc::new(BVHNode::new(&mut objects[first_half..], t0, t1))
-            };
-            (l, r)
-        };
-
-        // Compute bbox for this node
-        let bbox = if let (Some(lbb), Some(rbb)) = (left.bouding_box(t0, t1), right.bouding_box(t0, t1)) {
-            AABB::new_surrounding(lbb, &rbb)
-        } else {
-            panic!("No bounding box in BVHNode constructor");
-        };
-
-        BVHNode{
-            left,
-            right,
-            bbox
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distant_sphere_pdf_is_finite() {
+        let center = Vec3::new(0.0, 5.0, 0.0);
+        for &distance in &[10.0, 1e4, 1e6] {
+            let origin = Vec3::new(distance, 5.0, 0.0);
+            let pdf = sphere_pdf_value(&center, 0.3, &origin);
+            // The cone approaches a disk of area pi r² seen from `distance`
+            let expected = sqr(distance) / (std::f32::consts::PI * sqr(0.3));
+            assert!(pdf.is_finite() && (pdf / expected - 1.0).abs() < 1e-2, "{} at {}", pdf, distance);
+
+            // Samples spread over the cone instead of collapsing onto its axis
+            let axis = (center - origin).normalized();
+            let max_sin = (0..100).map(|_| {
+                let direction = sphere_sample_direction(&center, 0.3, &origin).unwrap();
+                assert!((direction.length() - 1.0).abs() < 1e-4);
+                let ray = Ray::new(origin, direction, 0.0);
+                assert!(sphere_intersect(&center, 0.3, &ray, 0.001, f32::MAX).is_some(), "sample missed at {}", distance);
+                direction.cross(&axis).length()
+            }).fold(0.0, f32::max);
+            let sin_theta_max = 0.3 / distance;
+            assert!(max_sin <= 1.001 * sin_theta_max && max_sin > 0.5 * sin_theta_max, "{} at {}", max_sin, distance);
         }
     }
-}
 
-impl Hittable for BVHNode {
-    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
-        if self.bbox.hit(r, t_min, t_max) {
-            let left_hit = self.left.hit(r, t_min, t_max);
-            let right_hit = self.right.hit(r, t_min, t_max);
-            
-            match (left_hit, right_hit) {
-                (Some(left_rec), Some(right_rec)) => {
-                    if left_rec.t < right_rec.t {
-                        return Some(left_rec);
-                    } else {
-                        return Some(right_rec);
-                    }
-                },
-                (Some(left_rec), None) => Some(left_rec),
-                (None, Some(right_rec)) => Some(right_rec),
-                (None, None) => None,
-            }
-        } else {
-            None
+    #[test]
+    fn small_distant_spheres_are_hit() {
+        // b² - ac alone loses the squared radius here
+        let center = Vec3::new(1.0, 2.0, 3.0);
+        for &(radius, distance) in &[(1e-3, 6.0), (0.3, 1e4), (1.0, 1e5)] {
+            let origin = center + Vec3::new(distance, 0.0, 0.0);
+            let ray = Ray::new(origin, center - origin, 0.0);
+            let t = sphere_intersect(&center, radius, &ray, 0.001, f32::MAX).unwrap_or_else(|| panic!("missed at {}", distance));
+            assert!((t - (1.0 - radius / distance)).abs() < 1e-5, "t {} at {}", t, distance);
         }
+
+        // Both roots from inside and in front
+        let ray = Ray::new(Vec3::zero(), Vec3::new(0.0, 0.0, 2.0), 0.0);
+        assert_eq!(sphere_intersect(&Vec3::zero(), 1.0, &ray, 0.001, f32::MAX), Some(0.5));
+        let ray = Ray::new(Vec3::new(0.0, 0.0, -3.0), Vec3::new(0.0, 0.0, 1.0), 0.0);
+        assert_eq!(sphere_intersect(&Vec3::zero(), 1.0, &ray, 0.001, f32::MAX), Some(2.0));
+        assert_eq!(sphere_intersect(&Vec3::zero(), 1.0, &ray, 2.5, f32::MAX), Some(4.0));
+        assert_eq!(sphere_intersect(&Vec3::zero(), 1.0, &ray, 4.5, f32::MAX), None);
     }
 
-    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
-        Some(self.bbox)
+    #[test]
+    fn sphere_pdf_is_zero_from_inside() {
+        assert_eq!(sphere_pdf_value(&Vec3::zero(), 1.0, &Vec3::new(0.5, 0.0, 0.0)), 0.0);
+        assert!(sphere_sample_direction(&Vec3::zero(), 1.0, &Vec3::zero()).is_none());
     }
 }
-
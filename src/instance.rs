@@ -0,0 +1,49 @@
+use crate::vec3::*;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::*;
+use crate::transform::{Matrix4, Transformed};
+use std::sync::Arc;
+
+/// Placed copy of shared geometry, the geometry and its BVH are stored only once however many instances there are
+pub struct Instance {
+    transformed: Transformed,
+    /// Replaces the materials of the geometry when set
+    material: Option<Arc<dyn Material>>,
+}
+
+impl Instance {
+    /// `transform` takes the geometry from its own space to the world and has to be invertible
+    pub fn new(geometry: Arc<dyn Hittable>, transform: Matrix4, material: Option<Arc<dyn Material>>) -> Instance {
+        Instance {
+            transformed: Transformed::new(geometry, transform),
+            material
+        }
+    }
+}
+
+impl Hittable for Instance {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let mut hit = self.transformed.hit(r, t_min, t_max)?;
+        if let Some(material) = &self.material {
+            hit.material = material.clone();
+        }
+        Some(hit)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.transformed.occluded(r, t_min, t_max)
+    }
+
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.transformed.bouding_box(t0, t1)
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.transformed.pdf_value(origin, direction, time)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        self.transformed.sample_direction(origin, time)
+    }
+}
@@ -0,0 +1,39 @@
+// Naming follows the paper notation (AABB, BVHNode)
+#![allow(clippy::upper_case_acronyms)]
+
+pub mod aabb;
+pub mod vec3;
+pub mod ray;
+pub mod hittable;
+pub mod bvh;
+pub mod wide_bvh;
+pub mod aggregate;
+pub mod transform;
+pub mod instance;
+pub mod animation;
+pub mod camera;
+pub mod texture;
+pub mod perlin;
+pub mod mesh;
+pub mod quad;
+pub mod obj;
+pub mod scene;
+pub mod random;
+pub mod presets;
+pub mod renderer;
+pub mod output;
+pub mod tonemap;
+
+pub use crate::vec3::Vec3;
+pub use crate::ray::Ray;
+pub use crate::camera::{Camera, CameraSettings};
+pub use crate::hittable::{Hittable, HitRecord, Material};
+pub use crate::bvh::{BVHNode, BVHSettings, BVHUpdate, FlatBVH};
+pub use crate::wide_bvh::{BVH4, BVH8};
+pub use crate::aggregate::Aggregate;
+pub use crate::transform::{Matrix4, Transformed};
+pub use crate::instance::Instance;
+pub use crate::animation::{Animated, AnimatedTransform, Keyframe};
+pub use crate::texture::Texture;
+pub use crate::scene::Scene;
+pub use crate::renderer::{Background, Framebuffer, RenderSettings, Renderer};
@@ -1,146 +1,173 @@
-use rayon::prelude::*;
-mod aabb;
-mod vec3;
-mod ray;
-mod hittable;
-mod camera;
-mod texture;
-
-extern crate rand;
+mod cli;
 
 use rand::Rng;
-use camera::Camera;
-use hittable::*;
-use vec3::{Vec3};
-use ray::Ray;
-use std::sync::Arc;
+use rusty_path::{presets, random, RenderSettings, Renderer};
+use rusty_path::scene::load_scene;
+use rusty_path::{Aggregate, BVHUpdate, Framebuffer, Hittable, Scene};
+use rusty_path::output::{write_exr, write_hdr, write_png, write_ppm};
 use std::time::{Instant};
-use texture::*;
-
-fn color(r: &Ray, world: Arc<dyn Hittable>, depth: i32) -> Vec3 {
-    // 0.001 to avoid self-intersections
-    if let Some(result) = world.hit(r, 0.001, std::f32::MAX) {
-        match &result.material.scatter(r, &result) {
-            Some((scatter_result, attenuation)) if depth < 50 => {
-                 attenuation * &color(&scatter_result, world, depth + 1)
-            }
-            _ => Vec3::zero()
-        }
-    } else {
-        let unit_dir = r.direction().normalized();
-        let t = 0.5 * (unit_dir.y() + 1.0);
-        (1.0 - t) * Vec3::one() + t * Vec3::new(0.5, 0.7, 1.0).to_linear()
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use cli::{Command, Options, OutputFormat, SceneSource, Sequence};
+
+fn write_image<W: Write>(mut out: W, image: &Framebuffer, options: &Options) -> io::Result<()> {
+    match options.format {
+        OutputFormat::Ppm => write_ppm(&mut out, image, &options.tone_map),
+        OutputFormat::Png => write_png(out, image, options.bit_depth, &options.tone_map),
+        OutputFormat::Exr => write_exr(&mut out, image, options.exr_type),
+        OutputFormat::Hdr => write_hdr(&mut out, image),
     }
 }
 
-fn random_scene() -> Arc<dyn Hittable> {
-    let mut items: Vec<Arc<dyn Hittable>> = vec![];
+/// None writes to stdout
+fn save_image(image: &Framebuffer, path: Option<&Path>, options: &Options) -> Result<(), String> {
+    let write_result = match path {
+        Some(path) => {
+            let file = File::create(path).map_err(|e| format!("cannot create {}: {}", path.display(), e))?;
+            write_image(BufWriter::new(file), image, options)
+        },
+        None => write_image(BufWriter::new(io::stdout().lock()), image, options),
+    };
+    write_result.map_err(|e| format!("cannot write the image: {}", e))
+}
 
-    let ground_texture = Box::new(CheckerTexture::new(
-        Box::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1).to_linear())),
-        Box::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9).to_linear())))
-    );
-    items.push(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(ground_texture)))));
-    
-    let mut rng = rand::thread_rng();
-    for a in -5..5 {
-        let a = 2.0 * a as f32;
-        for b in -5..5 {
-            let b = 2.0 * b as f32;
-            let choose_mat = rng.gen::<f32>();
-            let center = Vec3::new(a * 0.9 * rng.gen::<f32>(), 0.2, b + 0.9 * rng.gen::<f32>());
-            if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
-                if choose_mat < 0.8 {
-                    items.push(Arc::new(MovingSphere::new(
-                        center,
-                        center + Vec3::new(0.0, 0.5, 0.0),
-                        0.0,
-                        0.3,
-                        0.2,
-                        Arc::new(
-                            Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(rng.gen::<f32>() * rng.gen::<f32>(), rng.gen::<f32>() * rng.gen::<f32>(), rng.gen::<f32>() * rng.gen::<f32>()).to_linear())))))
-                        ),
-                    );
-                } else if choose_mat < 0.95 {
-                    items.push(Arc::new(Sphere::new(center, 0.2, Arc::new(
-                        Metal::new(Vec3::new(0.5 * (1.0 + rng.gen::<f32>()), 0.5 * (1.0 + rng.gen::<f32>()), 0.5 * (1.0 + rng.gen::<f32>())).to_linear(), 0.5 * rng.gen::<f32>()))))
-                    );
-                } else {
-                    items.push(Arc::new(Sphere::new(center, 0.2, Arc::new(Dielectric::new(1.5)))));
-                }
-            }
-        }
+/// Replaces the run of '#' in the file name by the zero padded frame number,
+/// names without '#' get `_####` in front of the extension
+fn frame_path(pattern: &Path, number: usize) -> PathBuf {
+    let name = pattern.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match name.rfind('.') {
+        _ if name.contains('#') => name,
+        Some(dot) if dot > 0 => format!("{}_####{}", &name[..dot], &name[dot..]),
+        _ => format!("{}_####", name),
+    };
+    let start = name.find('#').unwrap();
+    let width = name[start..].find(|c| c != '#').unwrap_or(name.len() - start);
+    pattern.with_file_name(format!("{}{:0width$}{}", &name[..start], number, &name[start + width..], width = width))
+}
+
+/// The BVH of the first frame is refitted to the motion during each following frame's shutter,
+/// and rebuilt when refitting made it too slow
+fn render_sequence(scene: &Scene, settings: RenderSettings, sequence: &Sequence, pattern: &Path, options: &Options) -> Result<(), String> {
+    let lights = scene.build_lights();
+    let frame_count = sequence.frame_count();
+    let mut aggregate: Option<Arc<Aggregate>> = None;
+    for frame in 0..frame_count {
+        let frame_start = Instant::now();
+        let time = sequence.frame_time(frame);
+        let (t0, t1) = (time, time + scene.camera.shutter_time);
+        let camera = scene.camera_at(time).build(settings.width as f32 / settings.height as f32);
+
+        // The previous frame's world is gone, so the aggregate is not shared anymore
+        let build = match aggregate.as_mut().and_then(Arc::get_mut) {
+            Some(existing) => match existing.update(t0, t1) {
+                Some(BVHUpdate::Refit) => "BVH refitted",
+                Some(BVHUpdate::Rebuild) => "BVH rebuilt",
+                None => "world kept",
+            },
+            None => {
+                aggregate = Some(Arc::new(scene.build_aggregate(t0, t1)));
+                "world built"
+            },
+        };
+        let world: Arc<dyn Hittable> = aggregate.clone().unwrap();
+        let build_duration = frame_start.elapsed();
+
+        // Different noise in every frame, still reproducible from the seed
+        let renderer = Renderer::new(RenderSettings { seed: random::mix_seed(settings.seed, frame as u64), ..settings });
+        let tracing_start = Instant::now();
+        let image = renderer.render_world(&world, &lights, &camera, &scene.background, time);
+        let tracing_duration = tracing_start.elapsed();
+
+        let path = frame_path(pattern, frame + 1);
+        save_image(&image, Some(&path), options)?;
+        eprintln!("Frame {}/{} at {:.3}s: {} in {:?}, traced in {:?}, written to {}",
+            frame + 1, frame_count, time, build, build_duration, tracing_duration, path.display());
     }
-    
-    items.push(Arc::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, Arc::new(Dielectric::new(1.5)))));
-    items.push(Arc::new(Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0, Arc::new(Lambertian::new(Box::new(ConstantTexture::new(Vec3::new(0.4, 0.2, 0.1).to_linear())))))));
-    items.push(Arc::new(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5).to_linear(), 0.0)))));
-    
-    eprintln!("Item count: {}", items.len());
-    //Arc::new(HittableList { items })
-    Arc::new(BVHNode::new(&mut items, 0.0, 1.0))
+    Ok(())
 }
 
-fn main() {
+fn run(options: Options) -> Result<(), String> {
     let setup_start = Instant::now();
-    const NX: usize = 800;
-    const NY: usize = 400;
-    let ns = 100;
 
-    println!("P3\n{} {}\n255", NX, NY);
+    if let Some(threads) = options.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .map_err(|e| format!("cannot create the thread pool: {}", e))?;
+    }
 
-    let look_from = Vec3::new(10.0, 2.0, 3.0);
-    let look_to = Vec3::new(4.0, 1.0, 1.0);
-    let focus_dist = (look_from - look_to).length();
+    let seed = options.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("Seed: {}", seed);
+    random::seed(seed);
 
-    let camera = Camera::new(&look_from, &look_to, &Vec3::up(), 20.0, NX as f32 / NY as f32, 0.1, focus_dist, 0.0);
-    /*let world = HittableList {items: vec![
-        Box::new(Sphere::new(Vec3::new(0.0, 0.0, -1.0), 0.5, Rc::new(Lambertian::new(Vec3::new(0.8, 0.3, 0.3).to_linear())))),
-        Box::new(Sphere::new(Vec3::new(0.0, -100.5, -1.0), 100.0, Rc::new(Lambertian::new(Vec3::new(0.8, 0.8, 0.0).to_linear())))),
-        Box::new(Sphere::new(Vec3::new(1.0, 0.0, -1.0), 0.5, Rc::new(Metal::new(Vec3::new(0.8, 0.6, 0.2).to_linear(), 0.1)))),
-        Box::new(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), 0.5, Rc::new(Dielectric::new(1.5)))),
-        //Box::new(Sphere::new(Vec3::new(-1.0, 0.0, -1.0), -0.45, Rc::new(Dielectric::new(1.5)))),
-    ]};*/
+    let scene = match &options.scene {
+        SceneSource::File(path) => load_scene(path).map_err(|e| e.to_string())?,
+        SceneSource::Preset(name) => presets::preset(name).ok_or_else(|| format!("unknown preset '{}'", name))?,
+    };
 
-    let world = random_scene();
+    let mut settings = RenderSettings::from_scene(&scene);
+    if let Some((width, height)) = options.resolution {
+        settings.width = width;
+        settings.height = height;
+    }
+    if let Some(samples) = options.samples {
+        settings.samples = samples;
+    }
+    settings.max_depth = options.max_depth;
+    settings.seed = seed;
+
+    eprintln!("Item count: {}", scene.objects.len());
+
+    if let (Some(sequence), Some(pattern)) = (&options.sequence, &options.output) {
+        eprintln!("Setup done in: {:?}", setup_start.elapsed());
+        render_sequence(&scene, settings, sequence, pattern, &options)?;
+        eprintln!("Done in: {:?}", setup_start.elapsed());
+        return Ok(());
+    }
+
+    let renderer = Renderer::new(settings);
+    let camera = scene.camera_at(0.0).build(settings.width as f32 / settings.height as f32);
+    let build_start = Instant::now();
+    let world = scene.build_world(0.0, scene.camera.shutter_time);
+    let build_duration = build_start.elapsed();
+    let lights = scene.build_lights();
 
-    let mut image = vec![(0, 0, 0); NX * NY];
     let setup_duration = setup_start.elapsed();
-    eprintln!("Setup done in: {:?}", setup_duration);
+    eprintln!("Setup done in: {:?} (BVH build: {:?})", setup_duration, scene.bvh_build_time + build_duration);
 
     let tracing_start = Instant::now();
-    image
-    .par_iter_mut()
-    .enumerate()
-    .for_each(|(i, (r, g, b))|{
-        let mut rng = rand::thread_rng();
-        let x = i % NX;
-        let y = NY - (i / NX);
-        
-        let mut col = Vec3::zero();
-        
-        for _ in 0..ns {
-            let u = (x as f32 + rng.gen::<f32>()) / NX as f32;
-            let v = (y as f32 + rng.gen::<f32>()) / NY as f32;
-            let r = camera.get_ray(u, v, 0.0);
-            col += &color(&r, world.clone(), 0);
-        }
-        col /= ns as f32;
-        col = col.to_srgb();
-        *r = (255.99 * col.r()) as i32;
-        *g = (255.99 * col.g()) as i32;
-        *b = (255.99 * col.b()) as i32;
-    });
+    let image = renderer.render_world(&world, &lights, &camera, &scene.background, 0.0);
     let tracing_duration = tracing_start.elapsed();
     eprintln!("Tracing done in: {:?}", tracing_duration);
 
     let image_writing_start = Instant::now();
-    for (r, g, b) in image.iter() {
-        println!("{} {} {}", r, g, b);
-    }
+    save_image(&image, options.output.as_deref(), &options)?;
     let image_writing_duration = image_writing_start.elapsed();
     eprintln!("Image writing done in: {:?}", image_writing_duration);
     
     let full_duration = setup_start.elapsed();
     eprintln!("Done in: {:?}", full_duration);
+    Ok(())
+}
+
+fn main() {
+    let command = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("error: {}\nRun with --help for usage.", e);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Help => print!("{}", cli::USAGE),
+        Command::Render(options) => {
+            if let Err(e) = run(options) {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 }
@@ -0,0 +1,512 @@
+use crate::vec3::*;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::*;
+use crate::wide_bvh::BVH8;
+use crate::random;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Meshes with at least this many triangles use the 8-wide BVH, see `cargo bench --bench bvh`
+const WIDE_BVH_THRESHOLD: usize = 1024;
+
+/// Flat triangles would produce a zero-thickness box on one axis which AABB::hit rejects
+pub(crate) const BBOX_PADDING: f32 = 0.0001;
+
+/// Shared vertex and index buffers of a triangle mesh
+pub struct Mesh {
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Vec3>>,
+    uvs: Option<Vec<(f32, f32)>>,
+    indices: Vec<[u32; 3]>,
+    material: Arc<dyn Material>,
+}
+
+impl Mesh {
+    /// Normals and UVs are per-vertex and indexed by the same indices as positions
+    pub fn new(positions: Vec<Vec3>, normals: Option<Vec<Vec3>>, uvs: Option<Vec<(f32, f32)>>, indices: Vec<[u32; 3]>, material: Arc<dyn Material>) -> Mesh {
+        if let Some(normals) = &normals {
+            assert_eq!(normals.len(), positions.len(), "Mesh needs exactly one normal per vertex");
+        }
+        if let Some(uvs) = &uvs {
+            assert_eq!(uvs.len(), positions.len(), "Mesh needs exactly one UV per vertex");
+        }
+        assert!(
+            indices.iter().flatten().all(|&i| (i as usize) < positions.len()),
+            "Mesh index out of bounds"
+        );
+
+        Mesh {
+            positions,
+            normals,
+            uvs,
+            indices,
+            material
+        }
+    }
+
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_deref()
+    }
+
+    pub fn uvs(&self) -> Option<&[(f32, f32)]> {
+        self.uvs.as_deref()
+    }
+
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+
+    pub fn material(&self) -> &Arc<dyn Material> {
+        &self.material
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.indices.len()
+    }
+
+    fn vertices(&self, triangle: usize) -> [Vec3; 3] {
+        let [a, b, c] = self.indices[triangle];
+        [self.positions[a as usize], self.positions[b as usize], self.positions[c as usize]]
+    }
+
+    pub fn area(&self, triangle: usize) -> f32 {
+        let [v0, v1, v2] = self.vertices(triangle);
+        0.5 * (v1 - v0).cross(&(v2 - v0)).length()
+    }
+
+    /// Uniformly distributed point on the triangle
+    pub fn sample_point(&self, triangle: usize) -> Vec3 {
+        let [v0, v1, v2] = self.vertices(triangle);
+        let mut rng = random::rng();
+        let su = rng.gen::<f32>().sqrt();
+        let b0 = 1.0 - su;
+        let b1 = rng.gen::<f32>() * su;
+        b0 * v0 + b1 * v1 + (1.0 - b0 - b1) * v2
+    }
+}
+
+/// Converts the area density of a surface point hit by a ray to solid angle density
+pub(crate) fn area_to_solid_angle_pdf(direction: &Vec3, hit: &HitRecord, area: f32) -> f32 {
+    let distance_sqr = sqr(hit.t) * direction.length_sqr();
+    let cosine = (direction.dot(&hit.geometric_normal) / direction.length()).abs();
+    if cosine <= 0.0 || area <= 0.0 {
+        return 0.0;
+    }
+    distance_sqr / (cosine * area)
+}
+
+/// Single triangle referencing a mesh, this is what gets put into the BVH
+pub struct Triangle {
+    mesh: Arc<Mesh>,
+    index: usize,
+}
+
+impl Triangle {
+    /// Standalone triangle, counter-clockwise winding defines the front face
+    pub fn new(a: Vec3, b: Vec3, c: Vec3, material: Arc<dyn Material>) -> Triangle {
+        Triangle {
+            mesh: Arc::new(Mesh::new(vec![a, b, c], None, None, vec![[0, 1, 2]], material)),
+            index: 0
+        }
+    }
+
+    pub fn from_mesh(mesh: Arc<Mesh>, index: usize) -> Triangle {
+        assert!(index < mesh.triangle_count(), "Triangle index out of bounds");
+        Triangle {
+            mesh,
+            index
+        }
+    }
+
+    /// Watertight ray-triangle intersection (Woop, Benthin, Wald 2013)
+    /// Returns t and barycentric weights of the three vertices
+    fn intersect(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, [f32; 3])> {
+        let [v0, v1, v2] = self.mesh.vertices(self.index);
+        let dir = r.direction().v();
+
+        // Permute axes so that z is the dominant ray direction and keep the winding
+        let kz = max_dimension(dir);
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if dir[kz] < 0.0 {
+            std::mem::swap(&mut kx, &mut ky);
+        }
+
+        // Shear so that the ray goes along +z
+        let sx = dir[kx] / dir[kz];
+        let sy = dir[ky] / dir[kz];
+        let sz = 1.0 / dir[kz];
+
+        let a = v0 - *r.origin();
+        let b = v1 - *r.origin();
+        let c = v2 - *r.origin();
+        let a = a.v();
+        let b = b.v();
+        let c = c.v();
+
+        let ax = a[kx] - sx * a[kz];
+        let ay = a[ky] - sy * a[kz];
+        let bx = b[kx] - sx * b[kz];
+        let by = b[ky] - sy * b[kz];
+        let cx = c[kx] - sx * c[kz];
+        let cy = c[ky] - sy * c[kz];
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+
+        // Edge hits are resolved in double precision so neighbouring triangles agree
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None;
+        }
+
+        let det = u + v + w;
+        if det == 0.0 {
+            return None;
+        }
+
+        // Exactly on an edge both triangles sharing it would be hit, only the one
+        // the edge goes up or left in takes it, like the top-left rule of rasterizers
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            let edges = [(u, bx, by, cx, cy), (v, cx, cy, ax, ay), (w, ax, ay, bx, by)];
+            for &(value, x0, y0, x1, y1) in &edges {
+                if value == 0.0 && !owns_edge(x1 - x0, y1 - y0, det) {
+                    return None;
+                }
+            }
+        }
+
+        let az = sz * a[kz];
+        let bz = sz * b[kz];
+        let cz = sz * c[kz];
+        let t = (u * az + v * bz + w * cz) / det;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        Some((t, [u / det, v / det, w / det]))
+    }
+
+    fn geometric_normal(&self) -> Vec3 {
+        let [v0, v1, v2] = self.mesh.vertices(self.index);
+        (v1 - v0).cross(&(v2 - v0)).normalized()
+    }
+
+    /// Interpolated texture coordinates, the barycentric coordinates of the second and third vertex without them
+    fn uv(&self, barycentric: &[f32; 3]) -> (f32, f32) {
+        match self.mesh.uvs() {
+            Some(uvs) => {
+                let [a, b, c] = self.mesh.indices[self.index];
+                let (a, b, c) = (uvs[a as usize], uvs[b as usize], uvs[c as usize]);
+                (
+                    barycentric[0] * a.0 + barycentric[1] * b.0 + barycentric[2] * c.0,
+                    barycentric[0] * a.1 + barycentric[1] * b.1 + barycentric[2] * c.1
+                )
+            },
+            None => (barycentric[1], barycentric[2])
+        }
+    }
+
+    /// Derivatives of the position along the texture coordinates, the edges from the first vertex without UVs
+    fn tangents(&self) -> (Vec3, Vec3) {
+        let [v0, v1, v2] = self.mesh.vertices(self.index);
+        let edges = (v1 - v0, v2 - v0);
+        let uvs = match self.mesh.uvs() {
+            Some(uvs) => uvs,
+            None => return edges,
+        };
+
+        let [a, b, c] = self.mesh.indices[self.index];
+        let (uv0, uv1, uv2) = (uvs[a as usize], uvs[b as usize], uvs[c as usize]);
+        let (du02, dv02) = (uv0.0 - uv2.0, uv0.1 - uv2.1);
+        let (du12, dv12) = (uv1.0 - uv2.0, uv1.1 - uv2.1);
+        let det = du02 * dv12 - dv02 * du12;
+        // UVs collapsed to a line or a point do not define a direction
+        if det.abs() < 1e-12 {
+            return edges;
+        }
+        let (dp02, dp12) = (v0 - v2, v1 - v2);
+        ((dv12 * dp02 - dv02 * dp12) / det, (du02 * dp12 - du12 * dp02) / det)
+    }
+
+    fn shading_normal(&self, barycentric: &[f32; 3]) -> Vec3 {
+        match self.mesh.normals() {
+            Some(normals) => {
+                let [a, b, c] = self.mesh.indices[self.index];
+                (barycentric[0] * normals[a as usize]
+                    + barycentric[1] * normals[b as usize]
+                    + barycentric[2] * normals[c as usize]).normalized()
+            },
+            None => self.geometric_normal()
+        }
+    }
+}
+
+/// Whether a hit on the edge going by (ex, ey) in the sheared ray space counts. The neighbour
+/// sharing the edge goes the other way around it, so exactly one of the two owns it
+fn owns_edge(ex: f32, ey: f32, det: f32) -> bool {
+    // Back facing triangles wind the other way around
+    let (ex, ey) = if det < 0.0 { (-ex, -ey) } else { (ex, ey) };
+    ey > 0.0 || (ey == 0.0 && ex < 0.0)
+}
+
+fn max_dimension(v: &[f32; 3]) -> usize {
+    let (x, y, z) = (v[0].abs(), v[1].abs(), v[2].abs());
+    if x > y && x > z {
+        0
+    } else if y > z {
+        1
+    } else {
+        2
+    }
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (t, barycentric) = self.intersect(r, t_min, t_max)?;
+        let normal = self.shading_normal(&barycentric);
+        // Vertex normals decide which side is the outside, even when the winding disagrees
+        let mut geometric_normal = self.geometric_normal();
+        if geometric_normal.dot(&normal) < 0.0 {
+            geometric_normal = -geometric_normal;
+        }
+        let (dpdu, dpdv) = self.tangents();
+        Some(HitRecord {
+            material: self.mesh.material.clone(),
+            p: r.point_at(t),
+            normal,
+            geometric_normal,
+            front_face: r.direction().dot(&geometric_normal) < 0.0,
+            t,
+            uv: self.uv(&barycentric),
+            dpdu,
+            dpdv
+        })
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.intersect(r, t_min, t_max).is_some()
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        let [v0, v1, v2] = self.mesh.vertices(self.index);
+        let mut bbox = AABB::new(v0, v0);
+        bbox.add(&AABB::new(v1, v1));
+        bbox.add(&AABB::new(v2, v2));
+        bbox.pad(BBOX_PADDING);
+        Some(bbox)
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        match self.hit(&Ray::new(*origin, *direction, time), 0.001, f32::MAX) {
+            Some(hit) => area_to_solid_angle_pdf(direction, &hit, self.mesh.area(self.index)),
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, _time: f32) -> Option<Vec3> {
+        Some(self.mesh.sample_point(self.index) - *origin)
+    }
+}
+
+/// Whole mesh as one hittable with its own BVH over the triangles
+pub struct TriangleMesh {
+    mesh: Arc<Mesh>,
+    root: Arc<dyn Hittable>,
+    /// Running sum of triangle areas for area weighted light sampling
+    area_cdf: Vec<f32>,
+}
+
+impl TriangleMesh {
+    pub fn new(mesh: Arc<Mesh>) -> TriangleMesh {
+        let triangles = TriangleMesh::triangles(&mesh);
+        let root: Arc<dyn Hittable> = match triangles.len() {
+            0 => panic!("TriangleMesh needs at least one triangle"),
+            1 => triangles[0].clone(),
+            n if n < WIDE_BVH_THRESHOLD => Arc::new(FlatBVH::new(&triangles, 0.0, 0.0).expect("Triangles are always bounded")),
+            _ => Arc::new(BVH8::new(&triangles, 0.0, 0.0).expect("Triangles are always bounded")),
+        };
+
+        let area_cdf = (0..mesh.triangle_count())
+            .scan(0.0, |sum, i| {
+                *sum += mesh.area(i);
+                Some(*sum)
+            })
+            .collect();
+
+        TriangleMesh {
+            mesh,
+            root,
+            area_cdf
+        }
+    }
+
+    /// Individual triangles of the mesh for putting them directly into a scene-wide BVH
+    pub fn triangles(mesh: &Arc<Mesh>) -> Vec<Arc<dyn Hittable>> {
+        (0..mesh.triangle_count())
+            .map(|i| Arc::new(Triangle::from_mesh(mesh.clone(), i)) as Arc<dyn Hittable>)
+            .collect()
+    }
+
+    pub fn mesh(&self) -> &Arc<Mesh> {
+        &self.mesh
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.root.hit(r, t_min, t_max)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.root.occluded(r, t_min, t_max)
+    }
+
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.root.bouding_box(t0, t1)
+    }
+
+    /// Sums over every surface point along the direction, `sample_direction` picks hidden ones as well
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        let total_area = *self.area_cdf.last().unwrap();
+        let ray = Ray::new(*origin, *direction, time);
+        let mut pdf = 0.0;
+        let mut t_min = 0.001;
+        // Hits have to be strictly behind t_min, so the same triangle is not found again
+        while let Some(hit) = self.hit(&ray, t_min, f32::MAX) {
+            pdf += area_to_solid_angle_pdf(direction, &hit, total_area);
+            t_min = hit.t;
+        }
+        pdf
+    }
+
+    fn sample_direction(&self, origin: &Vec3, _time: f32) -> Option<Vec3> {
+        let total_area = *self.area_cdf.last().unwrap();
+        let target = random::rng().gen::<f32>() * total_area;
+        let index = self.area_cdf.partition_point(|&sum| sum <= target).min(self.area_cdf.len() - 1);
+        Some(self.mesh.sample_point(index) - *origin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::ConstantTexture;
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::one()))))
+    }
+
+    fn triangles(positions: Vec<Vec3>, indices: Vec<[u32; 3]>) -> Vec<Triangle> {
+        let mesh = Arc::new(Mesh::new(positions, None, None, indices, material()));
+        (0..mesh.triangle_count()).map(|i| Triangle::from_mesh(mesh.clone(), i)).collect()
+    }
+
+    fn hit_count(triangles: &[Triangle], r: &Ray) -> usize {
+        triangles.iter().filter(|triangle| triangle.hit(r, 0.001, f32::MAX).is_some()).count()
+    }
+
+    /// Unit square in the z = 0 plane split along its diagonal, both halves wound the same way
+    fn square() -> Vec<Triangle> {
+        let positions = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        triangles(positions, vec![[0, 1, 2], [0, 2, 3]])
+    }
+
+    #[test]
+    fn shared_edges_are_hit_once() {
+        let square = square();
+        for &s in &[0.125, 0.25, 0.5, 0.75] {
+            let on_diagonal = Vec3::new(s, s, 0.0);
+            // Straight on the edge values are exactly zero in both triangles, from either side
+            for direction in &[Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.5, 0.5, -1.0), Vec3::new(-0.25, 0.25, 1.0)] {
+                let r = Ray::new(on_diagonal - *direction, *direction, 0.0);
+                assert_eq!(hit_count(&square, &r), 1, "through {:?} along {:?}", on_diagonal, direction);
+            }
+        }
+    }
+
+    #[test]
+    fn shared_vertices_are_hit_once() {
+        // Closed fan around the origin, every triangle shares the center
+        let mut positions = vec![Vec3::zero()];
+        let mut indices = vec![];
+        for i in 0..6u32 {
+            let angle = i as f32 * std::f32::consts::PI / 3.0;
+            positions.push(Vec3::new(angle.cos(), angle.sin(), 0.0));
+            indices.push([0, 1 + i, 1 + (i + 1) % 6]);
+        }
+        let fan = triangles(positions, indices);
+        for direction in &[Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.25, 0.5, -1.0)] {
+            let r = Ray::new(-*direction, *direction, 0.0);
+            assert_eq!(hit_count(&fan, &r), 1, "along {:?}", direction);
+        }
+
+        // The vertices where the diagonal of the square ends are hit at most once
+        let square = square();
+        for corner in &[Vec3::zero(), Vec3::new(1.0, 1.0, 0.0)] {
+            let r = Ray::new(*corner + Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+            assert!(hit_count(&square, &r) <= 1, "through {:?}", corner);
+        }
+    }
+
+    #[test]
+    fn rays_near_the_shared_edge_do_not_fall_through() {
+        let square = square();
+        let mut rng = random::rng();
+        random::seed(7);
+        for _ in 0..10000 {
+            // Points within a few ulps of the diagonal, seen from random directions
+            let s = rng.gen::<f32>();
+            let target = Vec3::new(s, s + (rng.gen::<f32>() - 0.5) * 1e-6, 0.0);
+            let direction = Vec3::new(rng.gen::<f32>() - 0.5, rng.gen::<f32>() - 0.5, -1.0);
+            let r = Ray::new(target - 3.0 * direction, direction, 0.0);
+            assert_eq!(hit_count(&square, &r), 1, "towards {:?}", target);
+        }
+    }
+
+    #[test]
+    fn barycentric_weights_and_t() {
+        let triangle = Triangle::new(Vec3::new(0.0, 0.0, -2.0), Vec3::new(4.0, 0.0, -2.0), Vec3::new(0.0, 2.0, -2.0), material());
+        let r = Ray::new(Vec3::new(1.0, 0.5, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let (t, barycentric) = triangle.intersect(&r, 0.001, f32::MAX).unwrap();
+        assert!((t - 2.0).abs() < 1e-6);
+        // p = 0.5 a + 0.25 b + 0.25 c
+        for (weight, expected) in barycentric.iter().zip(&[0.5, 0.25, 0.25]) {
+            assert!((weight - expected).abs() < 1e-6, "{:?}", barycentric);
+        }
+        assert!(triangle.intersect(&r, 0.001, 1.5).is_none());
+        assert!(triangle.intersect(&Ray::new(Vec3::new(3.0, 1.5, 0.0), Vec3::new(0.0, 0.0, -1.0), 0.0), 0.001, f32::MAX).is_none());
+
+        // Without texture coordinates the UV are the weights of the second and third vertex
+        let hit = triangle.hit(&r, 0.001, f32::MAX).unwrap();
+        assert!((hit.uv.0 - 0.25).abs() < 1e-6 && (hit.uv.1 - 0.25).abs() < 1e-6, "{:?}", hit.uv);
+        assert!((hit.p - Vec3::new(1.0, 0.5, -2.0)).length() < 1e-6);
+        assert!(hit.front_face && (hit.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1e-6);
+    }
+
+    #[test]
+    fn interpolated_uvs_and_tangents() {
+        let positions = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 4.0, 0.0)];
+        let uvs = vec![(0.5, 0.5), (1.0, 0.5), (0.5, 1.5)];
+        let mesh = Arc::new(Mesh::new(positions, None, Some(uvs), vec![[0, 1, 2]], material()));
+        let triangle = Triangle::from_mesh(mesh, 0);
+        let r = Ray::new(Vec3::new(0.5, 1.0, 1.0), Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let hit = triangle.hit(&r, 0.001, f32::MAX).unwrap();
+        // Weights 0.5, 0.25 and 0.25
+        assert!((hit.uv.0 - 0.625).abs() < 1e-6 && (hit.uv.1 - 0.75).abs() < 1e-6, "{:?}", hit.uv);
+        // u grows by 0.5 over 2 units of x and v by 1 over 4 units of y
+        assert!((hit.dpdu - Vec3::new(4.0, 0.0, 0.0)).length() < 1e-5, "{:?}", hit.dpdu);
+        assert!((hit.dpdv - Vec3::new(0.0, 4.0, 0.0)).length() < 1e-5, "{:?}", hit.dpdv);
+    }
+}
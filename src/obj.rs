@@ -0,0 +1,490 @@
+use crate::vec3::*;
+use crate::hittable::*;
+use crate::texture::*;
+use crate::mesh::{Mesh, TriangleMesh};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Error while loading an OBJ or MTL file, line is None for errors not tied to a line (e.g. IO)
+#[derive(Debug)]
+pub struct ObjError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ObjError {
+    fn new(path: &Path, line: usize, message: String) -> ObjError {
+        ObjError {
+            path: path.to_path_buf(),
+            line: Some(line),
+            message
+        }
+    }
+
+    fn io(path: &Path, error: std::io::Error) -> ObjError {
+        ObjError {
+            path: path.to_path_buf(),
+            line: None,
+            message: error.to_string()
+        }
+    }
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl Error for ObjError {}
+
+/// One `g`/`o` group with a single material
+pub struct ObjMesh {
+    pub name: String,
+    pub mesh: Arc<Mesh>,
+}
+
+/// Material as written in the MTL file, before mapping it onto our materials
+struct MtlMaterial {
+    kd: Vec3,
+    ks: Vec3,
+    ns: f32,
+    ni: f32,
+    d: f32,
+    map_kd: Option<Arc<dyn Texture>>,
+}
+
+impl MtlMaterial {
+    fn new() -> MtlMaterial {
+        MtlMaterial {
+            kd: Vec3::new_fill(0.8),
+            ks: Vec3::zero(),
+            ns: 0.0,
+            ni: 1.0,
+            d: 1.0,
+            map_kd: None
+        }
+    }
+
+    /// Colors are used as they are, MTL stores reflectances and not display colors
+    fn to_material(&self) -> Arc<dyn Material> {
+        if self.d < 1.0 {
+            let ref_idx = if self.ni > 1.0 { self.ni } else { 1.5 };
+            return Arc::new(Dielectric::new(ref_idx));
+        }
+
+        if max_component(&self.ks) > max_component(&self.kd) {
+            // Blinn-Phong exponent to roughness, Ns of 0 is fully rough and 1000 almost a mirror
+            let roughness = clamp((2.0 / (self.ns + 2.0)).sqrt(), 0.0, 1.0);
+            return Arc::new(Metal::new(self.ks, roughness));
+        }
+
+        // The texture replaces Kd, exporters usually leave Kd at some default next to it
+        match &self.map_kd {
+            Some(map_kd) => Arc::new(Lambertian::new(map_kd.clone())),
+            None => Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(self.kd)))),
+        }
+    }
+}
+
+fn max_component(v: &Vec3) -> f32 {
+    v.x().max(v.y()).max(v.z())
+}
+
+fn parse_floats(path: &Path, line: usize, tokens: &[&str], min: usize, max: usize) -> Result<Vec<f32>, ObjError> {
+    if tokens.len() < min || tokens.len() > max {
+        return Err(ObjError::new(path, line, format!("expected {} to {} numbers, got {}", min, max, tokens.len())));
+    }
+
+    tokens.iter()
+        .map(|t| t.parse::<f32>().map_err(|_| ObjError::new(path, line, format!("invalid number '{}'", t))))
+        .collect()
+}
+
+fn parse_vec3(path: &Path, line: usize, tokens: &[&str]) -> Result<Vec3, ObjError> {
+    let v = parse_floats(path, line, tokens, 3, 3)?;
+    Ok(Vec3::new(v[0], v[1], v[2]))
+}
+
+fn load_mtl(path: &Path, materials: &mut HashMap<String, Arc<dyn Material>>) -> Result<(), ObjError> {
+    let source = fs::read_to_string(path).map_err(|e| ObjError::io(path, e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut current: Option<(String, MtlMaterial)> = None;
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_num = line_idx + 1;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+
+        if tokens[0] == "newmtl" {
+            if let Some((name, mtl)) = current.take() {
+                materials.insert(name, mtl.to_material());
+            }
+            if tokens.len() < 2 {
+                return Err(ObjError::new(path, line_num, "newmtl without a name".to_string()));
+            }
+            current = Some((tokens[1..].join(" "), MtlMaterial::new()));
+            continue;
+        }
+
+        let mtl = match &mut current {
+            Some((_, mtl)) => mtl,
+            None => return Err(ObjError::new(path, line_num, format!("'{}' before any newmtl", tokens[0]))),
+        };
+        let args = &tokens[1..];
+        match tokens[0] {
+            "Kd" => mtl.kd = parse_vec3(path, line_num, args)?,
+            "Ks" => mtl.ks = parse_vec3(path, line_num, args)?,
+            "Ns" => mtl.ns = parse_floats(path, line_num, args, 1, 1)?[0],
+            "Ni" => mtl.ni = parse_floats(path, line_num, args, 1, 1)?[0],
+            "d" => mtl.d = parse_floats(path, line_num, args, 1, 1)?[0],
+            "Tr" => mtl.d = 1.0 - parse_floats(path, line_num, args, 1, 1)?[0],
+            "map_Kd" => {
+                // Options like -bm come before the file name which is always last
+                let file = match args.last() {
+                    Some(file) => dir.join(file),
+                    None => return Err(ObjError::new(path, line_num, "map_Kd without a file name".to_string())),
+                };
+                let texture = ImageTexture::load(&file, WrapMode::Repeat, Filter::Bilinear)
+                    .map_err(|e| ObjError::new(path, line_num, e))?;
+                mtl.map_kd = Some(Arc::new(texture));
+            },
+            // Ambient, emission, illumination model and other maps have no counterpart in our materials
+            _ => ()
+        }
+    }
+
+    if let Some((name, mtl)) = current.take() {
+        materials.insert(name, mtl.to_material());
+    }
+
+    Ok(())
+}
+
+/// Faces of one group/material pair with vertices deduplicated by their (v, vt, vn) triple
+struct MeshBuilder {
+    name: String,
+    material: Arc<dyn Material>,
+    vertex_map: HashMap<(usize, Option<usize>, Option<usize>), u32>,
+    positions: Vec<Vec3>,
+    normals: Vec<Option<Vec3>>,
+    uvs: Vec<Option<(f32, f32)>>,
+    indices: Vec<[u32; 3]>,
+}
+
+impl MeshBuilder {
+    fn new(name: String, material: Arc<dyn Material>) -> MeshBuilder {
+        MeshBuilder {
+            name,
+            material,
+            vertex_map: HashMap::new(),
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            indices: vec![]
+        }
+    }
+
+    fn vertex(&mut self, key: (usize, Option<usize>, Option<usize>), data: &ObjData) -> u32 {
+        if let Some(&index) = self.vertex_map.get(&key) {
+            return index;
+        }
+
+        let index = self.positions.len() as u32;
+        self.positions.push(data.positions[key.0]);
+        self.uvs.push(key.1.map(|i| data.uvs[i]));
+        self.normals.push(key.2.map(|i| data.normals[i]));
+        self.vertex_map.insert(key, index);
+        index
+    }
+
+    /// Normals are only used when all vertices have one, missing UVs are filled with zeros
+    fn build(self) -> Option<ObjMesh> {
+        if self.indices.is_empty() {
+            return None;
+        }
+
+        let normals: Option<Vec<Vec3>> = self.normals.into_iter().collect();
+        let uvs = if self.uvs.iter().any(|uv| uv.is_some()) {
+            Some(self.uvs.into_iter().map(|uv| uv.unwrap_or((0.0, 0.0))).collect())
+        } else {
+            None
+        };
+
+        Some(ObjMesh {
+            name: self.name,
+            mesh: Arc::new(Mesh::new(self.positions, normals, uvs, self.indices, self.material))
+        })
+    }
+}
+
+struct ObjData {
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+}
+
+/// Resolves a 1-based or negative (relative to the end) OBJ index
+fn resolve_index(path: &Path, line: usize, token: &str, count: usize) -> Result<usize, ObjError> {
+    let index = token.parse::<i64>()
+        .map_err(|_| ObjError::new(path, line, format!("invalid index '{}'", token)))?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+
+    if index == 0 || resolved < 0 || resolved >= count as i64 {
+        return Err(ObjError::new(path, line, format!("index {} out of range, only {} defined so far", index, count)));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_face_vertex(path: &Path, line: usize, token: &str, data: &ObjData) -> Result<(usize, Option<usize>, Option<usize>), ObjError> {
+    let mut parts = token.split('/');
+    let v = resolve_index(path, line, parts.next().unwrap_or(""), data.positions.len())?;
+    let vt = match parts.next() {
+        Some(t) if !t.is_empty() => Some(resolve_index(path, line, t, data.uvs.len())?),
+        _ => None,
+    };
+    let vn = match parts.next() {
+        Some(t) if !t.is_empty() => Some(resolve_index(path, line, t, data.normals.len())?),
+        _ => None,
+    };
+    if parts.next().is_some() {
+        return Err(ObjError::new(path, line, format!("invalid face vertex '{}'", token)));
+    }
+
+    Ok((v, vt, vn))
+}
+
+/// Loads an OBJ file, every group/material combination becomes one mesh
+/// Faces without `usemtl` get the `default_material`, quads and n-gons are fan triangulated
+pub fn load_obj_meshes(path: &Path, default_material: Arc<dyn Material>) -> Result<Vec<ObjMesh>, ObjError> {
+    let source = fs::read_to_string(path).map_err(|e| ObjError::io(path, e))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut data = ObjData {
+        positions: vec![],
+        normals: vec![],
+        uvs: vec![],
+    };
+    let mut materials: HashMap<String, Arc<dyn Material>> = HashMap::new();
+    let mut builders: Vec<MeshBuilder> = vec![];
+    let mut group = String::from("default");
+    let mut material = default_material.clone();
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_num = line_idx + 1;
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+
+        let args = &tokens[1..];
+        match tokens[0] {
+            "v" => {
+                // Optional w is ignored
+                let v = parse_floats(path, line_num, args, 3, 4)?;
+                data.positions.push(Vec3::new(v[0], v[1], v[2]));
+            },
+            "vn" => data.normals.push(parse_vec3(path, line_num, args)?.normalized()),
+            "vt" => {
+                let v = parse_floats(path, line_num, args, 1, 3)?;
+                data.uvs.push((v[0], *v.get(1).unwrap_or(&0.0)));
+            },
+            "f" => {
+                if args.len() < 3 {
+                    return Err(ObjError::new(path, line_num, format!("face needs at least 3 vertices, got {}", args.len())));
+                }
+
+                let needs_builder = match builders.last() {
+                    Some(b) => b.name != group || !Arc::ptr_eq(&b.material, &material),
+                    None => true,
+                };
+                if needs_builder {
+                    builders.push(MeshBuilder::new(group.clone(), material.clone()));
+                }
+                let builder = builders.last_mut().unwrap();
+
+                let mut face = Vec::with_capacity(args.len());
+                for token in args {
+                    let key = parse_face_vertex(path, line_num, token, &data)?;
+                    face.push(builder.vertex(key, &data));
+                }
+                for i in 1..face.len() - 1 {
+                    builder.indices.push([face[0], face[i], face[i + 1]]);
+                }
+            },
+            "g" | "o" => {
+                group = if args.is_empty() { String::from("default") } else { args.join(" ") };
+            },
+            "usemtl" => {
+                let name = args.join(" ");
+                material = match materials.get(&name) {
+                    Some(m) => m.clone(),
+                    None => return Err(ObjError::new(path, line_num, format!("unknown material '{}'", name))),
+                };
+            },
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err(ObjError::new(path, line_num, "mtllib without a file name".to_string()));
+                }
+                for file in args {
+                    load_mtl(&dir.join(file), &mut materials)?;
+                }
+            },
+            // Smoothing groups, lines, points and free-form geometry are not supported
+            _ => ()
+        }
+    }
+
+    Ok(builders.into_iter().filter_map(MeshBuilder::build).collect())
+}
+
+/// Loads an OBJ file into one TriangleMesh per group/material
+pub fn load_obj(path: &Path, default_material: Arc<dyn Material>) -> Result<Vec<Arc<dyn Hittable>>, ObjError> {
+    Ok(load_obj_meshes(path, default_material)?
+        .into_iter()
+        .map(|m| Arc::new(TriangleMesh::new(m.mesh)) as Arc<dyn Hittable>)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray::Ray;
+
+    /// Writes the files into a directory of their own, returns the path of the first one
+    fn write_files(test: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rusty_path_obj_{}_{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        dir.join(files[0].0)
+    }
+
+    fn white() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::one()))))
+    }
+
+    fn load(test: &str, obj: &str) -> Result<Vec<ObjMesh>, ObjError> {
+        load_obj_meshes(&write_files(test, &[("test.obj", obj.as_bytes())]), white())
+    }
+
+    /// Albedo of a diffuse material at the texture coordinates `uv`
+    fn albedo(material: &Arc<dyn Material>, uv: (f32, f32)) -> Vec3 {
+        let hit = HitRecord {
+            material: material.clone(),
+            p: Vec3::zero(),
+            normal: Vec3::up(),
+            geometric_normal: Vec3::up(),
+            front_face: true,
+            t: 1.0,
+            uv,
+            dpdu: Vec3::right(),
+            dpdv: Vec3::forward()
+        };
+        let ray = Ray::new(Vec3::up(), -Vec3::up(), 0.0);
+        material.scatter(&ray, &hit).unwrap().1
+    }
+
+    #[test]
+    fn triangulates_polygons_as_fans() {
+        let meshes = load("fan", "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0.5 1.5 0\nv 0 1 0\nf 1 2 3 4 5\n").unwrap();
+        assert_eq!(meshes.len(), 1);
+        assert_eq!(meshes[0].mesh.indices(), &[[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    #[test]
+    fn resolves_negative_indices_relative_to_the_end() {
+        let obj = "v 5 5 5\nv 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nvt 1 0\nvt 0 1\nf -3/-3 -2/-2 -1/-1\n";
+        let mesh = &load("negative", obj).unwrap()[0].mesh;
+        let positions: Vec<[f32; 3]> = mesh.positions().iter().map(|p| *p.v()).collect();
+        assert_eq!(positions, vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]);
+        assert_eq!(mesh.uvs().unwrap(), &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]);
+    }
+
+    #[test]
+    fn shares_vertices_and_splits_groups() {
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\ng a\nf 1 2 3\nf 1 3 4\ng b\nf 1 2 4\n";
+        let meshes = load("groups", obj).unwrap();
+        assert_eq!(meshes.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(meshes[0].mesh.positions().len(), 4);
+        assert_eq!(meshes[1].mesh.positions().len(), 3);
+    }
+
+    #[test]
+    fn reports_malformed_lines_with_their_number() {
+        let error = load("malformed", "v 0 0 0\nv 1 0\n").err().unwrap();
+        assert_eq!(error.line, Some(2));
+        assert!(error.message.contains("expected 3 to 4 numbers"), "{}", error);
+
+        let error = load("bad_index", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 x\n").err().unwrap();
+        assert_eq!(error.line, Some(4));
+        assert!(error.message.contains("invalid index 'x'"), "{}", error);
+
+        let error = load("bad_vertex", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3///\n").err().unwrap();
+        assert!(error.message.contains("invalid face vertex"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_out_of_range_indices_and_small_faces() {
+        let error = load("range", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 4\n").err().unwrap();
+        assert_eq!(error.line, Some(4));
+        assert!(error.message.contains("index 4 out of range"), "{}", error);
+
+        assert!(load("zero", "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 0 1 2\n").is_err());
+        assert!(load("before_end", "v 0 0 0\nv 1 0 0\nf -3 -2 -1\n").is_err());
+
+        let error = load("small_face", "v 0 0 0\nv 1 0 0\nf 1 2\n").err().unwrap();
+        assert!(error.message.contains("at least 3 vertices"), "{}", error);
+    }
+
+    #[test]
+    fn reports_unknown_materials_and_missing_files() {
+        let error = load("usemtl", "v 0 0 0\nusemtl nothing\n").err().unwrap();
+        assert_eq!(error.line, Some(2));
+        assert!(error.message.contains("unknown material 'nothing'"), "{}", error);
+
+        let error = load("mtllib", "mtllib missing.mtl\n").err().unwrap();
+        assert!(error.path.ends_with("missing.mtl"));
+        assert_eq!(error.line, None);
+    }
+
+    #[test]
+    fn maps_map_kd_onto_an_image_texture() {
+        // Red and green pixel, 255 stays 1 when decoded from sRGB
+        let mut ppm = b"P6\n2 1\n255\n".to_vec();
+        ppm.extend_from_slice(&[255, 0, 0, 0, 255, 0]);
+        let obj = "mtllib test.mtl\nv 0 0 0\nv 1 0 0\nv 0 1 0\nusemtl pic\nf 1 2 3\n";
+        let mtl = "newmtl pic\nKd 0.8 0.8 0.8\nmap_Kd -bm 1 pic.ppm\n";
+        let path = write_files("map_kd", &[("test.obj", obj.as_bytes()), ("test.mtl", mtl.as_bytes()), ("pic.ppm", &ppm)]);
+        let meshes = load_obj_meshes(&path, white()).unwrap();
+
+        let material = meshes[0].mesh.material();
+        assert_eq!(*albedo(material, (0.25, 0.5)).v(), [1.0, 0.0, 0.0]);
+        assert_eq!(*albedo(material, (0.75, 0.5)).v(), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn reports_missing_textures_at_the_map_kd_line() {
+        let obj = "mtllib test.mtl\n";
+        let mtl = "newmtl pic\nKd 0.8 0.8 0.8\nmap_Kd missing.png\n";
+        let path = write_files("missing_map_kd", &[("test.obj", obj.as_bytes()), ("test.mtl", mtl.as_bytes())]);
+        let error = load_obj_meshes(&path, white()).err().unwrap();
+        assert!(error.path.ends_with("test.mtl"));
+        assert_eq!(error.line, Some(3));
+    }
+}
@@ -0,0 +1,480 @@
+use crate::renderer::Framebuffer;
+use crate::tonemap::ToneMapper;
+use std::io::{self, Write};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PngBitDepth {
+    Eight,
+    Sixteen,
+}
+
+/// Linear radiance to 8-bit sRGB after tone mapping
+pub fn to_rgb8(image: &Framebuffer, tone_map: &ToneMapper) -> Vec<[u8; 3]> {
+    image.pixels().iter().map(|col| {
+        let col = tone_map.map(*col).to_srgb();
+        [
+            (255.99 * col.r()) as u8,
+            (255.99 * col.g()) as u8,
+            (255.99 * col.b()) as u8,
+        ]
+    }).collect()
+}
+
+/// Linear radiance to 16-bit sRGB after tone mapping
+pub fn to_rgb16(image: &Framebuffer, tone_map: &ToneMapper) -> Vec<[u16; 3]> {
+    image.pixels().iter().map(|col| {
+        let col = tone_map.map(*col).to_srgb();
+        [
+            (65535.99 * col.r()) as u16,
+            (65535.99 * col.g()) as u16,
+            (65535.99 * col.b()) as u16,
+        ]
+    }).collect()
+}
+
+/// ASCII PPM (P3)
+pub fn write_ppm<W: Write>(out: &mut W, image: &Framebuffer, tone_map: &ToneMapper) -> io::Result<()> {
+    writeln!(out, "P3\n{} {}\n255", image.width(), image.height())?;
+    for [r, g, b] in to_rgb8(image, tone_map) {
+        writeln!(out, "{} {} {}", r, g, b)?;
+    }
+    out.flush()
+}
+
+/// sRGB PNG, 16-bit samples are stored big-endian as the format requires
+pub fn write_png<W: Write>(out: W, image: &Framebuffer, bit_depth: PngBitDepth, tone_map: &ToneMapper) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, image.width() as u32, image.height() as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_source_srgb(png::SrgbRenderingIntent::Perceptual);
+
+    let data: Vec<u8> = match bit_depth {
+        PngBitDepth::Eight => {
+            encoder.set_depth(png::BitDepth::Eight);
+            to_rgb8(image, tone_map).into_iter().flatten().collect()
+        },
+        PngBitDepth::Sixteen => {
+            encoder.set_depth(png::BitDepth::Sixteen);
+            to_rgb16(image, tone_map).into_iter().flatten().flat_map(u16::to_be_bytes).collect()
+        },
+    };
+
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    writer.write_image_data(&data).map_err(png_error)?;
+    writer.finish().map_err(png_error)
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+/// Radiance HDR with flat (uncompressed) RGBE scanlines, keeps linear values
+pub fn write_hdr<W: Write>(out: &mut W, image: &Framebuffer) -> io::Result<()> {
+    write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", image.height(), image.width())?;
+    for col in image.pixels() {
+        out.write_all(&to_rgbe(col.r(), col.g(), col.b()))?;
+    }
+    out.flush()
+}
+
+/// Shared exponent encoding, the mantissas are relative to the brightest component
+fn to_rgbe(r: f32, g: f32, b: f32) -> [u8; 4] {
+    let r = r.max(0.0);
+    let g = g.max(0.0);
+    let b = b.max(0.0);
+    let v = r.max(g).max(b);
+    if v < 1e-32 || !v.is_finite() {
+        return [0, 0, 0, 0];
+    }
+
+    // v = m * 2^e with m in [0.5, 1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 * 2f32.powi(-e);
+    [
+        (r * scale).min(255.0) as u8,
+        (g * scale).min(255.0) as u8,
+        (b * scale).min(255.0) as u8,
+        (e + 128) as u8,
+    ]
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ExrPixelType {
+    Half,
+    Float,
+}
+
+/// One named image plane, values are stored row by row from the top
+pub struct ExrChannel<'a> {
+    pub name: &'a str,
+    pub values: &'a [f32],
+}
+
+/// OpenEXR with the R, G and B channels of the framebuffer
+pub fn write_exr<W: Write>(out: &mut W, image: &Framebuffer, pixel_type: ExrPixelType) -> io::Result<()> {
+    let pixels = image.pixels();
+    let r: Vec<f32> = pixels.iter().map(|c| c.r()).collect();
+    let g: Vec<f32> = pixels.iter().map(|c| c.g()).collect();
+    let b: Vec<f32> = pixels.iter().map(|c| c.b()).collect();
+    write_exr_channels(out, image.width(), image.height(), &[
+        ExrChannel { name: "R", values: &r },
+        ExrChannel { name: "G", values: &g },
+        ExrChannel { name: "B", values: &b },
+    ], pixel_type)
+}
+
+/// Uncompressed scanline OpenEXR with arbitrary channels, e.g. for AOVs next to the color
+pub fn write_exr_channels<W: Write>(out: &mut W, width: usize, height: usize, channels: &[ExrChannel], pixel_type: ExrPixelType) -> io::Result<()> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    if width == 0 || height == 0 || channels.is_empty() {
+        return Err(invalid("EXR image needs a non-zero size and at least one channel".to_string()));
+    }
+
+    // The format requires channels sorted by name, both in the header and in the pixel data
+    let mut channels: Vec<&ExrChannel> = channels.iter().collect();
+    channels.sort_by(|a, b| a.name.cmp(b.name));
+    for (i, channel) in channels.iter().enumerate() {
+        if channel.name.is_empty() || channel.name.contains('\0') {
+            return Err(invalid(format!("invalid EXR channel name '{}'", channel.name)));
+        }
+        if i > 0 && channels[i - 1].name == channel.name {
+            return Err(invalid(format!("duplicate EXR channel '{}'", channel.name)));
+        }
+        if channel.values.len() != width * height {
+            return Err(invalid(format!("EXR channel '{}' has {} values, expected {}", channel.name, channel.values.len(), width * height)));
+        }
+    }
+
+    let (type_id, sample_size): (i32, usize) = match pixel_type {
+        ExrPixelType::Half => (1, 2),
+        ExrPixelType::Float => (2, 4),
+    };
+
+    let mut header = vec![];
+    // Magic number and version 2 with single-part scanline flags
+    header.extend_from_slice(&20000630i32.to_le_bytes());
+    header.extend_from_slice(&2i32.to_le_bytes());
+
+    let mut chlist = vec![];
+    for channel in &channels {
+        chlist.extend_from_slice(channel.name.as_bytes());
+        chlist.push(0);
+        chlist.extend_from_slice(&type_id.to_le_bytes());
+        // pLinear and reserved bytes, then x and y sampling
+        chlist.extend_from_slice(&[0, 0, 0, 0]);
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+        chlist.extend_from_slice(&1i32.to_le_bytes());
+    }
+    chlist.push(0);
+
+    let mut window = vec![];
+    for v in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        window.extend_from_slice(&v.to_le_bytes());
+    }
+
+    add_exr_attribute(&mut header, "channels", "chlist", &chlist);
+    add_exr_attribute(&mut header, "compression", "compression", &[0]);
+    add_exr_attribute(&mut header, "dataWindow", "box2i", &window);
+    add_exr_attribute(&mut header, "displayWindow", "box2i", &window);
+    add_exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+    add_exr_attribute(&mut header, "pixelAspectRatio", "float", &1f32.to_le_bytes());
+    add_exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+    add_exr_attribute(&mut header, "screenWindowWidth", "float", &1f32.to_le_bytes());
+    header.push(0);
+    out.write_all(&header)?;
+
+    // Offset table with one uncompressed scanline per chunk
+    let line_size = width * channels.len() * sample_size;
+    let chunk_size = 8 + line_size;
+    let table_end = header.len() + height * 8;
+    for y in 0..height {
+        out.write_all(&((table_end + y * chunk_size) as u64).to_le_bytes())?;
+    }
+
+    let mut line = Vec::with_capacity(chunk_size);
+    for y in 0..height {
+        line.clear();
+        line.extend_from_slice(&(y as i32).to_le_bytes());
+        line.extend_from_slice(&(line_size as i32).to_le_bytes());
+        for channel in &channels {
+            for &value in &channel.values[y * width..(y + 1) * width] {
+                match pixel_type {
+                    ExrPixelType::Half => line.extend_from_slice(&f32_to_f16(value).to_le_bytes()),
+                    ExrPixelType::Float => line.extend_from_slice(&value.to_le_bytes()),
+                }
+            }
+        }
+        out.write_all(&line)?;
+    }
+
+    out.flush()
+}
+
+fn add_exr_attribute(header: &mut Vec<u8>, name: &str, type_name: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(type_name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// IEEE 754 half precision with round to nearest even, overflows go to infinity
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan;
+    }
+
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    if half_exponent <= 0 {
+        // Subnormal half, everything below half of the smallest subnormal is zero
+        if half_exponent < -10 {
+            return sign;
+        }
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - half_exponent) as u32;
+        let mut half = mantissa >> shift;
+        let rest = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        if rest > halfway || (rest == halfway && half & 1 != 0) {
+            half += 1;
+        }
+        return sign | half as u16;
+    }
+
+    // A carry from rounding correctly moves into the exponent
+    let mut half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let rest = mantissa & 0x1fff;
+    if rest > 0x1000 || (rest == 0x1000 && half & 1 != 0) {
+        half += 1;
+    }
+    sign | half as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::Vec3;
+    use std::convert::TryInto;
+
+    /// Exact half to single conversion to check the encoder against
+    fn f16_to_f32(half: u16) -> f32 {
+        let sign = if half & 0x8000 != 0 { -1.0 } else { 1.0 };
+        let exponent = i32::from((half >> 10) & 0x1f);
+        let mantissa = f32::from(half & 0x3ff);
+        match exponent {
+            0 => sign * mantissa * 2f32.powi(-24),
+            0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+            0x1f => f32::NAN,
+            _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+        }
+    }
+
+    #[test]
+    fn half_round_trips_every_value() {
+        for half in 0..=u16::MAX {
+            let value = f16_to_f32(half);
+            if !value.is_nan() {
+                assert_eq!(f32_to_f16(value), half, "{:#06x} {}", half, value);
+            }
+        }
+    }
+
+    #[test]
+    fn half_rounds_to_nearest_even() {
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(-2.0), 0xc000);
+        // Halfway between two halves goes to the even mantissa
+        assert_eq!(f32_to_f16(1.0 + 2f32.powi(-11)), 0x3c00);
+        assert_eq!(f32_to_f16(1.0 + 3.0 * 2f32.powi(-11)), 0x3c02);
+        assert_eq!(f32_to_f16(1.0 + 1.1 * 2f32.powi(-11)), 0x3c01);
+        // The carry of the largest mantissa moves into the exponent
+        assert_eq!(f32_to_f16(2.0 - 2f32.powi(-12)), 0x4000);
+    }
+
+    #[test]
+    fn half_subnormals() {
+        assert_eq!(f32_to_f16(2f32.powi(-24)), 0x0001);
+        assert_eq!(f32_to_f16(-2f32.powi(-24)), 0x8001);
+        assert_eq!(f32_to_f16(2f32.powi(-25)), 0x0000);
+        assert_eq!(f32_to_f16(1.5 * 2f32.powi(-25)), 0x0001);
+        assert_eq!(f32_to_f16(3.0 * 2f32.powi(-25)), 0x0002);
+        assert_eq!(f32_to_f16(2f32.powi(-14) - 2f32.powi(-24)), 0x03ff);
+        // Rounding up from the largest subnormal gives the smallest normal
+        assert_eq!(f32_to_f16(2f32.powi(-14) - 2f32.powi(-25)), 0x0400);
+        assert_eq!(f32_to_f16(1e-10), 0x0000);
+        assert_eq!(f32_to_f16(-1e-10), 0x8000);
+        assert_eq!(f32_to_f16(f32::MIN_POSITIVE / 2.0), 0x0000);
+    }
+
+    #[test]
+    fn half_overflow_and_nan() {
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65519.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(1e10), 0x7c00);
+        assert_eq!(f32_to_f16(-1e10), 0xfc00);
+        assert_eq!(f32_to_f16(f32::INFINITY), 0x7c00);
+        assert_eq!(f32_to_f16(f32::NEG_INFINITY), 0xfc00);
+        let nan = f32_to_f16(f32::NAN);
+        assert!(nan & 0x7c00 == 0x7c00 && nan & 0x3ff != 0, "{:#06x}", nan);
+    }
+
+    #[test]
+    fn rgbe_round_trips_through_the_hdr_decoder() {
+        let colors = [
+            Vec3::new(0.0, 0.0, 0.0),
+            Vec3::new(1.0, 0.5, 0.25),
+            Vec3::new(0.001, 0.002, 0.003),
+            Vec3::new(1000.0, 1.0, 0.0),
+            Vec3::new(255.0, 256.0, 257.0),
+            Vec3::new(-1.0, 2.0, 0.5),
+        ];
+        let mut image = Framebuffer::new(3, 2);
+        image.pixels_mut().copy_from_slice(&colors);
+        let mut file = vec![];
+        write_hdr(&mut file, &image).unwrap();
+
+        // The generic image loader would tone map to 8 bits, the decoder itself keeps the values
+        let decoder = image::codecs::hdr::HdrDecoder::new(&file[..]).unwrap();
+        let metadata = decoder.metadata();
+        assert_eq!((metadata.width, metadata.height), (3, 2));
+        let decoded = decoder.read_image_hdr().unwrap();
+        assert_eq!(decoded.len(), colors.len());
+        for (color, pixel) in colors.iter().zip(&decoded) {
+            // The mantissas have 8 bits relative to the brightest component, negative values are clamped
+            let expected = [color.r().max(0.0), color.g().max(0.0), color.b().max(0.0)];
+            let tolerance = expected.iter().cloned().fold(0.0, f32::max) / 128.0;
+            for c in 0..3 {
+                assert!((pixel[c] - expected[c]).abs() <= tolerance, "{:?} decoded as {:?}", color, pixel);
+            }
+        }
+    }
+
+    fn read_i32(data: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    fn read_str(data: &[u8], at: usize) -> &str {
+        let end = at + data[at..].iter().position(|&b| b == 0).unwrap();
+        std::str::from_utf8(&data[at..end]).unwrap()
+    }
+
+    /// Name, type and value of a header attribute
+    type Attribute<'a> = (&'a str, &'a str, &'a [u8]);
+
+    /// Attributes of the header and the offset right after it
+    fn exr_attributes(data: &[u8]) -> (Vec<Attribute<'_>>, usize) {
+        let mut attributes = vec![];
+        let mut at = 8;
+        loop {
+            let name = read_str(data, at);
+            at += name.len() + 1;
+            if name.is_empty() {
+                return (attributes, at);
+            }
+            let type_name = read_str(data, at);
+            at += type_name.len() + 1;
+            let size = read_i32(data, at) as usize;
+            at += 4;
+            attributes.push((name, type_name, &data[at..at + size]));
+            at += size;
+        }
+    }
+
+    #[test]
+    fn exr_header_lists_sorted_channels() {
+        let values = [0.0; 6];
+        let channels = [
+            ExrChannel { name: "Z", values: &values },
+            ExrChannel { name: "B", values: &values },
+            ExrChannel { name: "G", values: &values },
+        ];
+        let mut file = vec![];
+        write_exr_channels(&mut file, 3, 2, &channels, ExrPixelType::Half).unwrap();
+
+        assert_eq!(read_i32(&file, 0), 20000630);
+        assert_eq!(read_i32(&file, 4), 2);
+        let (attributes, _) = exr_attributes(&file);
+        let names: Vec<&str> = attributes.iter().map(|(name, _, _)| *name).collect();
+        assert_eq!(names, vec!["channels", "compression", "dataWindow", "displayWindow", "lineOrder",
+            "pixelAspectRatio", "screenWindowCenter", "screenWindowWidth"]);
+
+        let (_, type_name, chlist) = attributes[0];
+        assert_eq!(type_name, "chlist");
+        let mut at = 0;
+        let mut listed = vec![];
+        while chlist[at] != 0 {
+            let name = read_str(chlist, at);
+            at += name.len() + 1;
+            assert_eq!(read_i32(chlist, at), 1, "half pixel type of {}", name);
+            assert_eq!((read_i32(chlist, at + 8), read_i32(chlist, at + 12)), (1, 1));
+            at += 16;
+            listed.push(name);
+        }
+        assert_eq!(at, chlist.len() - 1);
+        assert_eq!(listed, vec!["B", "G", "Z"]);
+
+        let (_, type_name, window) = attributes[2];
+        assert_eq!(type_name, "box2i");
+        let window: Vec<i32> = (0..4).map(|i| read_i32(window, 4 * i)).collect();
+        assert_eq!(window, vec![0, 0, 2, 1]);
+        assert_eq!(attributes[1].2, &[0]);
+    }
+
+    #[test]
+    fn exr_offset_table_points_at_scanlines() {
+        let (width, height) = (3, 4);
+        let red: Vec<f32> = (0..width * height).map(|i| i as f32).collect();
+        let green: Vec<f32> = (0..width * height).map(|i| -(i as f32)).collect();
+        let channels = [
+            ExrChannel { name: "R", values: &red },
+            ExrChannel { name: "G", values: &green },
+        ];
+        let mut file = vec![];
+        write_exr_channels(&mut file, width, height, &channels, ExrPixelType::Float).unwrap();
+
+        let (_, header_end) = exr_attributes(&file);
+        let line_size = width * 2 * 4;
+        let offsets: Vec<usize> = (0..height)
+            .map(|y| u64::from_le_bytes(file[header_end + 8 * y..header_end + 8 * y + 8].try_into().unwrap()) as usize)
+            .collect();
+        assert_eq!(offsets[0], header_end + 8 * height);
+        assert_eq!(offsets[height - 1] + 8 + line_size, file.len());
+
+        let read_f32 = |at: usize| f32::from_bits(read_i32(&file, at) as u32);
+        for (y, &offset) in offsets.iter().enumerate() {
+            assert_eq!(read_i32(&file, offset), y as i32);
+            assert_eq!(read_i32(&file, offset + 4) as usize, line_size);
+            // G comes before R, each channel holds a whole row
+            for x in 0..width {
+                assert_eq!(read_f32(offset + 8 + 4 * x), green[y * width + x]);
+                assert_eq!(read_f32(offset + 8 + 4 * (width + x)), red[y * width + x]);
+            }
+        }
+    }
+
+    #[test]
+    fn exr_rejects_bad_channels() {
+        let values = [0.0; 4];
+        let short = [0.0; 3];
+        let mut file = vec![];
+        assert!(write_exr_channels(&mut file, 2, 2, &[], ExrPixelType::Half).is_err());
+        assert!(write_exr_channels(&mut file, 2, 2, &[ExrChannel { name: "R", values: &short }], ExrPixelType::Half).is_err());
+        let duplicate = [ExrChannel { name: "R", values: &values }, ExrChannel { name: "R", values: &values }];
+        assert!(write_exr_channels(&mut file, 2, 2, &duplicate, ExrPixelType::Half).is_err());
+        assert!(file.is_empty());
+    }
+}
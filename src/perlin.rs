@@ -0,0 +1,94 @@
+use crate::vec3::*;
+use rand::{Rng, SeedableRng};
+use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+
+/// Lattice size, the noise repeats every this many units
+const POINT_COUNT: usize = 256;
+
+/// Wraps lattice coordinates, negative ones included
+const POINT_MASK: i64 = POINT_COUNT as i64 - 1;
+
+const _: () = assert!(POINT_COUNT.is_power_of_two(), "Lattice coordinates are wrapped with a mask");
+
+/// Gradient noise of Perlin with random unit gradients on the lattice points
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    perm_x: Vec<usize>,
+    perm_y: Vec<usize>,
+    perm_z: Vec<usize>,
+}
+
+impl Perlin {
+    /// The same seed always gives the same noise, independent of the render seed
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let gradients = (0..POINT_COUNT)
+            .map(|_| loop {
+                let v = Vec3::new(rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0), rng.gen_range(-1.0, 1.0));
+                // Rejection keeps the directions uniform instead of bunching up at the cube corners
+                let length_sqr = v.length_sqr();
+                if length_sqr > 1e-6 && length_sqr <= 1.0 {
+                    break v.normalized();
+                }
+            })
+            .collect();
+        let mut permutation = || {
+            let mut perm: Vec<usize> = (0..POINT_COUNT).collect();
+            perm.shuffle(&mut rng);
+            perm
+        };
+        let perm_x = permutation();
+        let perm_y = permutation();
+        let perm_z = permutation();
+
+        Perlin {
+            gradients,
+            perm_x,
+            perm_y,
+            perm_z
+        }
+    }
+
+    /// Smooth noise roughly in -1..1, zero on the lattice points
+    pub fn noise(&self, p: &Vec3) -> f32 {
+        let (fx, fy, fz) = (p.x().floor(), p.y().floor(), p.z().floor());
+        let (u, v, w) = (p.x() - fx, p.y() - fy, p.z() - fz);
+        let (i, j, k) = (fx as i64, fy as i64, fz as i64);
+
+        // Hermite smoothing hides the lattice, the gradients alone would show it
+        let (uu, vv, ww) = (u * u * (3.0 - 2.0 * u), v * v * (3.0 - 2.0 * v), w * w * (3.0 - 2.0 * w));
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let gradient = self.gradients[
+                        self.perm_x[((i + di) & POINT_MASK) as usize]
+                            ^ self.perm_y[((j + dj) & POINT_MASK) as usize]
+                            ^ self.perm_z[((k + dk) & POINT_MASK) as usize]
+                    ];
+                    let (di, dj, dk) = (di as f32, dj as f32, dk as f32);
+                    let offset = Vec3::new(u - di, v - dj, w - dk);
+                    sum += (di * uu + (1.0 - di) * (1.0 - uu))
+                        * (dj * vv + (1.0 - dj) * (1.0 - vv))
+                        * (dk * ww + (1.0 - dk) * (1.0 - ww))
+                        * gradient.dot(&offset);
+                }
+            }
+        }
+        sum
+    }
+
+    /// Sum of the absolute noise over `octaves`, each one `lacunarity` times the frequency and `gain` times the weight of the previous
+    pub fn turbulence(&self, p: &Vec3, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let mut sum = 0.0;
+        let mut point = *p;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            sum += weight * self.noise(&point).abs();
+            point = lacunarity * point;
+            weight *= gain;
+        }
+        sum
+    }
+}
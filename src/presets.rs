@@ -0,0 +1,151 @@
+use rand::Rng;
+use crate::camera::CameraSettings;
+use crate::hittable::*;
+use crate::vec3::Vec3;
+use crate::texture::*;
+use crate::scene::Scene;
+use crate::quad::{BoxShape, Quad};
+use crate::transform::{Matrix4, Transformed};
+use crate::renderer::Background;
+use crate::random;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const PRESETS: &[&str] = &["random", "cornell"];
+
+/// Built-in scenes which do not need a scene file
+pub fn preset(name: &str) -> Option<Scene> {
+    match name {
+        "random" => Some(random_scene()),
+        "cornell" => Some(cornell_box()),
+        _ => None,
+    }
+}
+
+fn random_scene() -> Scene {
+    let mut items: Vec<Arc<dyn Hittable>> = vec![];
+
+    let ground_texture = Arc::new(CheckerTexture::new(
+        Arc::new(ConstantTexture::new(Vec3::new(0.2, 0.3, 0.1).to_linear())),
+        Arc::new(ConstantTexture::new(Vec3::new(0.9, 0.9, 0.9).to_linear())))
+    );
+    items.push(Arc::new(Sphere::new(Vec3::new(0.0, -1000.0, 0.0), 1000.0, Arc::new(Lambertian::new(ground_texture)))));
+    
+    let mut rng = random::rng();
+    for a in -5..5 {
+        let a = 2.0 * a as f32;
+        for b in -5..5 {
+            let b = 2.0 * b as f32;
+            let choose_mat = rng.gen::<f32>();
+            let center = Vec3::new(a * 0.9 * rng.gen::<f32>(), 0.2, b + 0.9 * rng.gen::<f32>());
+            if (center - Vec3::new(4.0, 0.2, 0.0)).length() > 0.9 {
+                if choose_mat < 0.8 {
+                    items.push(Arc::new(MovingSphere::new(
+                        center,
+                        center + Vec3::new(0.0, 0.5, 0.0),
+                        0.0,
+                        0.3,
+                        0.2,
+                        Arc::new(
+                            Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(rng.gen::<f32>() * rng.gen::<f32>(), rng.gen::<f32>() * rng.gen::<f32>(), rng.gen::<f32>() * rng.gen::<f32>()).to_linear())))))
+                        ),
+                    );
+                } else if choose_mat < 0.95 {
+                    items.push(Arc::new(Sphere::new(center, 0.2, Arc::new(
+                        Metal::new(Vec3::new(0.5 * (1.0 + rng.gen::<f32>()), 0.5 * (1.0 + rng.gen::<f32>()), 0.5 * (1.0 + rng.gen::<f32>())).to_linear(), 0.5 * rng.gen::<f32>()))))
+                    );
+                } else {
+                    items.push(Arc::new(Sphere::new(center, 0.2, Arc::new(Dielectric::new(1.5)))));
+                }
+            }
+        }
+    }
+    
+    items.push(Arc::new(Sphere::new(Vec3::new(0.0, 1.0, 0.0), 1.0, Arc::new(Dielectric::new(1.5)))));
+    items.push(Arc::new(Sphere::new(Vec3::new(-4.0, 1.0, 0.0), 1.0, Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.4, 0.2, 0.1).to_linear())))))));
+    items.push(Arc::new(Sphere::new(Vec3::new(4.0, 1.0, 0.0), 1.0, Arc::new(Metal::new(Vec3::new(0.7, 0.6, 0.5).to_linear(), 0.0)))));
+    
+    const NX: usize = 800;
+    const NY: usize = 400;
+
+    let look_from = Vec3::new(10.0, 2.0, 3.0);
+    let look_to = Vec3::new(4.0, 1.0, 1.0);
+    let focus_dist = (look_from - look_to).length();
+
+    Scene {
+        width: NX,
+        height: NY,
+        samples: 100,
+        camera: CameraSettings {
+            look_from,
+            look_at: look_to,
+            up: Vec3::up(),
+            fov: 20.0,
+            aperture: 0.1,
+            focus_dist,
+            shutter_time: 0.0
+        },
+        objects: items,
+        lights: vec![],
+        use_bvh: true,
+        background: Background::Sky,
+        camera_animation: None,
+        bvh_build_time: Duration::default()
+    }
+}
+
+/// Quad with the corner a and its neighbours b and d, counter-clockwise winding a, b, d defines the front face
+fn quad(a: Vec3, b: Vec3, d: Vec3, material: Arc<dyn Material>) -> Arc<dyn Hittable> {
+    Arc::new(Quad::new(a, b - a, d - a, material))
+}
+
+/// Box standing on the floor with its corner at `offset`, rotated about the y axis around the corner
+fn block(size: Vec3, angle: f32, offset: Vec3, material: Arc<dyn Material>) -> Arc<dyn Hittable> {
+    let shape = Arc::new(BoxShape::new(Vec3::zero(), size, material));
+    Arc::new(Transformed::new(shape, Matrix4::translation(offset) * Matrix4::rotation(Vec3::up(), angle)))
+}
+
+fn cornell_box() -> Scene {
+    let red: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.65, 0.05, 0.05)))));
+    let white: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.73, 0.73, 0.73)))));
+    let green: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::new(0.12, 0.45, 0.15)))));
+    let light: Arc<dyn Material> = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(Vec3::new_fill(15.0))), false));
+
+    let lamp = quad(Vec3::new(213.0, 554.0, 227.0), Vec3::new(343.0, 554.0, 227.0), Vec3::new(213.0, 554.0, 332.0), light);
+
+    // All walls face the inside of the box
+    let items = vec![
+        quad(Vec3::new(555.0, 0.0, 0.0), Vec3::new(555.0, 0.0, 555.0), Vec3::new(555.0, 555.0, 0.0), green),
+        quad(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 555.0, 0.0), Vec3::new(0.0, 0.0, 555.0), red),
+        lamp.clone(),
+        quad(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 555.0), Vec3::new(555.0, 0.0, 0.0), white.clone()),
+        quad(Vec3::new(0.0, 555.0, 0.0), Vec3::new(555.0, 555.0, 0.0), Vec3::new(0.0, 555.0, 555.0), white.clone()),
+        quad(Vec3::new(0.0, 0.0, 555.0), Vec3::new(0.0, 555.0, 555.0), Vec3::new(555.0, 0.0, 555.0), white.clone()),
+        block(Vec3::new(165.0, 330.0, 165.0), 15.0, Vec3::new(265.0, 0.0, 295.0), white.clone()),
+        block(Vec3::new(165.0, 165.0, 165.0), -18.0, Vec3::new(130.0, 0.0, 65.0), white),
+    ];
+
+    let look_from = Vec3::new(278.0, 278.0, -800.0);
+    let look_at = Vec3::new(278.0, 278.0, 0.0);
+
+    Scene {
+        width: 600,
+        height: 600,
+        samples: 100,
+        camera: CameraSettings {
+            look_from,
+            look_at,
+            up: Vec3::up(),
+            fov: 40.0,
+            aperture: 0.0,
+            focus_dist: (look_from - look_at).length(),
+            shutter_time: 0.0
+        },
+        objects: items,
+        lights: vec![lamp],
+        use_bvh: true,
+        background: Background::Color(Vec3::zero()),
+        camera_animation: None,
+        bvh_build_time: Duration::default()
+    }
+}
@@ -0,0 +1,165 @@
+use crate::vec3::*;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::*;
+use crate::mesh::{area_to_solid_angle_pdf, BBOX_PADDING};
+use crate::random;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Parallelogram spanned by the edges `u` and `v` from `origin`, the front face is on the side of u × v
+pub struct Quad {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    normal: Vec3,
+    /// Plane offset along the normal
+    d: f32,
+    /// Maps points in the plane to their edge coordinates, (u × v) / |u × v|²
+    w: Vec3,
+    area: f32,
+    material: Arc<dyn Material>,
+}
+
+impl Quad {
+    /// `u` and `v` have to span an area
+    pub fn new(origin: Vec3, u: Vec3, v: Vec3, material: Arc<dyn Material>) -> Quad {
+        let n = u.cross(&v);
+        assert!(n.length_sqr() > 0.0, "Quad edges have to span an area");
+        let normal = n.normalized();
+        Quad {
+            origin,
+            u,
+            v,
+            normal,
+            d: normal.dot(&origin),
+            w: n / n.length_sqr(),
+            area: n.length(),
+            material
+        }
+    }
+
+    /// Ray parameter and the edge coordinates of the hit point, which are also its UV
+    fn intersect(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<(f32, f32, f32)> {
+        let denominator = self.normal.dot(r.direction());
+        if denominator == 0.0 {
+            return None;
+        }
+        let t = (self.d - self.normal.dot(r.origin())) / denominator;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+
+        let planar = r.point_at(t) - self.origin;
+        let alpha = self.w.dot(&planar.cross(&self.v));
+        let beta = self.w.dot(&self.u.cross(&planar));
+        if !(0.0..=1.0).contains(&alpha) || !(0.0..=1.0).contains(&beta) {
+            return None;
+        }
+        Some((t, alpha, beta))
+    }
+}
+
+impl Hittable for Quad {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let (t, alpha, beta) = self.intersect(r, t_min, t_max)?;
+        Some(HitRecord {
+            material: self.material.clone(),
+            p: r.point_at(t),
+            normal: self.normal,
+            geometric_normal: self.normal,
+            front_face: r.direction().dot(&self.normal) < 0.0,
+            t,
+            uv: (alpha, beta),
+            dpdu: self.u,
+            dpdv: self.v
+        })
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.intersect(r, t_min, t_max).is_some()
+    }
+
+    /// Padded on the axes the quad is flat in
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        let mut bbox = AABB::new(self.origin, self.origin);
+        for corner in &[self.origin + self.u, self.origin + self.v, self.origin + self.u + self.v] {
+            bbox.add(&AABB::new(*corner, *corner));
+        }
+        bbox.pad(BBOX_PADDING);
+        Some(bbox)
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        match self.hit(&Ray::new(*origin, *direction, time), 0.001, f32::MAX) {
+            Some(hit) => area_to_solid_angle_pdf(direction, &hit, self.area),
+            None => 0.0,
+        }
+    }
+
+    fn sample_direction(&self, origin: &Vec3, _time: f32) -> Option<Vec3> {
+        let mut rng = random::rng();
+        let point = self.origin + rng.gen::<f32>() * self.u + rng.gen::<f32>() * self.v;
+        Some(point - *origin)
+    }
+}
+
+/// Axis aligned box from six quads facing outwards
+pub struct BoxShape {
+    sides: HittableList,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl BoxShape {
+    /// `a` and `b` are opposite corners and have to differ on every axis
+    pub fn new(a: Vec3, b: Vec3, material: Arc<dyn Material>) -> BoxShape {
+        assert!(!(b - a).v().contains(&0.0), "Box can not be flat");
+        let min = Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z()));
+        let max = Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z()));
+        let dx = Vec3::new(max.x() - min.x(), 0.0, 0.0);
+        let dy = Vec3::new(0.0, max.y() - min.y(), 0.0);
+        let dz = Vec3::new(0.0, 0.0, max.z() - min.z());
+
+        let side = |origin: Vec3, u: Vec3, v: Vec3| Arc::new(Quad::new(origin, u, v, material.clone())) as Arc<dyn Hittable>;
+        let sides = vec![
+            side(Vec3::new(min.x(), min.y(), max.z()), dx, dy),
+            side(Vec3::new(max.x(), min.y(), max.z()), -dz, dy),
+            side(Vec3::new(max.x(), min.y(), min.z()), -dx, dy),
+            side(min, dz, dy),
+            side(Vec3::new(min.x(), max.y(), max.z()), dx, -dz),
+            side(min, dx, dz),
+        ];
+
+        BoxShape {
+            sides: HittableList { items: sides },
+            min,
+            max
+        }
+    }
+}
+
+impl Hittable for BoxShape {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        self.sides.hit(r, t_min, t_max)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        self.sides.occluded(r, t_min, t_max)
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        let mut bbox = AABB::new(self.min, self.max);
+        bbox.pad(BBOX_PADDING);
+        Some(bbox)
+    }
+
+    /// A side is picked uniformly, so the density is the average over the sides
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        self.sides.pdf_value(origin, direction, time)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        self.sides.sample_direction(origin, time)
+    }
+}
@@ -0,0 +1,47 @@
+use rand::{Error, RngCore, SeedableRng};
+use rand::rngs::SmallRng;
+use std::cell::RefCell;
+
+thread_local! {
+    static RNG: RefCell<SmallRng> = RefCell::new(SmallRng::from_entropy());
+}
+
+/// Handle to the seedable generator of the current thread, use it like `rand::thread_rng()`
+#[derive(Copy, Clone)]
+pub struct SeededRng;
+
+impl RngCore for SeededRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RNG.with(|rng| rng.borrow_mut().fill_bytes(dest))
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        RNG.with(|rng| rng.borrow_mut().try_fill_bytes(dest))
+    }
+}
+
+pub fn rng() -> SeededRng {
+    SeededRng
+}
+
+/// Reseeds the generator of the current thread
+pub fn seed(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = SmallRng::seed_from_u64(seed));
+}
+
+/// Derives an independent seed for e.g. a pixel so that results do not depend on thread scheduling
+pub fn mix_seed(seed: u64, index: u64) -> u64 {
+    // SplitMix64 finalizer
+    let mut z = seed ^ index.wrapping_add(1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
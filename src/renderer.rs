@@ -0,0 +1,265 @@
+use rayon::prelude::*;
+use rand::Rng;
+use crate::vec3::Vec3;
+use crate::ray::Ray;
+use crate::camera::Camera;
+use crate::hittable::{Hittable, HitRecord};
+use crate::scene::Scene;
+use crate::random;
+use std::sync::Arc;
+
+/// Shadow rays stop this fraction before the light so they do not hit the light itself
+const SHADOW_EPSILON: f32 = 0.0001;
+
+#[derive(Copy, Clone, Debug)]
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    pub samples: u32,
+    pub max_depth: u32,
+    pub seed: u64,
+}
+
+impl RenderSettings {
+    /// Resolution and samples stored in the scene, the rest has defaults
+    pub fn from_scene(scene: &Scene) -> RenderSettings {
+        RenderSettings {
+            width: scene.width,
+            height: scene.height,
+            samples: scene.samples,
+            max_depth: 50,
+            seed: 0
+        }
+    }
+}
+
+/// Linear radiance, rows go from the top of the image
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Vec3::zero(); width * height]
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [Vec3] {
+        &mut self.pixels
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vec3 {
+        self.pixels[y * self.width + x]
+    }
+}
+
+/// Radiance of rays which do not hit anything
+#[derive(Copy, Clone, Debug)]
+pub enum Background {
+    /// White to blue gradient from the book
+    Sky,
+    Color(Vec3),
+}
+
+impl Background {
+    pub fn value(&self, r: &Ray) -> Vec3 {
+        match self {
+            Background::Sky => {
+                let unit_dir = r.direction().normalized();
+                let t = 0.5 * (unit_dir.y() + 1.0);
+                (1.0 - t) * Vec3::one() + t * Vec3::new(0.5, 0.7, 1.0).to_linear()
+            },
+            Background::Color(color) => *color,
+        }
+    }
+}
+
+pub struct Renderer {
+    settings: RenderSettings,
+}
+
+impl Renderer {
+    pub fn new(settings: RenderSettings) -> Renderer {
+        Renderer {
+            settings
+        }
+    }
+
+    pub fn settings(&self) -> &RenderSettings {
+        &self.settings
+    }
+
+    /// Builds the acceleration structure and renders the scene with the camera matching the settings resolution
+    pub fn render(&self, scene: &Scene) -> Framebuffer {
+        self.render_frame(scene, 0.0)
+    }
+
+    /// Renders the frame whose shutter opens at `time`, the acceleration structure is built for just this frame
+    pub fn render_frame(&self, scene: &Scene, time: f32) -> Framebuffer {
+        let camera = scene.camera_at(time).build(self.settings.width as f32 / self.settings.height as f32);
+        let world = scene.build_world(time, time + scene.camera.shutter_time);
+        let lights = scene.build_lights();
+        self.render_world(&world, &lights, &camera, &scene.background, time)
+    }
+
+    /// Renders on the current rayon thread pool, rays get times from `time_start` to the end of the camera shutter.
+    /// `lights` are sampled directly at every diffuse bounce, they have to be part of `world` as well
+    pub fn render_world(&self, world: &Arc<dyn Hittable>, lights: &dyn Hittable, camera: &Camera, background: &Background, time_start: f32) -> Framebuffer {
+        let nx = self.settings.width;
+        let ny = self.settings.height;
+        let ns = self.settings.samples;
+        let max_depth = self.settings.max_depth;
+        let seed = self.settings.seed;
+
+        let mut image = Framebuffer::new(nx, ny);
+        image.pixels
+        .par_iter_mut()
+        .enumerate()
+        .for_each(|(i, pixel)|{
+            // Seeding per pixel keeps the image independent of the thread count
+            random::seed(random::mix_seed(seed, i as u64));
+            let mut rng = random::rng();
+            let x = i % nx;
+            let y = ny - (i / nx);
+
+            let mut col = Vec3::zero();
+
+            for _ in 0..ns {
+                let u = (x as f32 + rng.gen::<f32>()) / nx as f32;
+                let v = (y as f32 + rng.gen::<f32>()) / ny as f32;
+                let r = camera.get_ray(u, v, time_start);
+                col += &color(&r, world, lights, background, None, 0, max_depth);
+            }
+            col /= ns as f32;
+            *pixel = col;
+        });
+
+        image
+    }
+}
+
+/// `bsdf_pdf` is the density of the previous bounce generating `r`, None for camera rays and specular bounces
+fn color(r: &Ray, world: &Arc<dyn Hittable>, lights: &dyn Hittable, background: &Background, bsdf_pdf: Option<f32>, depth: u32, max_depth: u32) -> Vec3 {
+    // 0.001 to avoid self-intersections
+    if let Some(result) = world.hit(r, 0.001, f32::MAX) {
+        let mut emitted = result.material.emitted(r, &result);
+        if let Some(bsdf_pdf) = bsdf_pdf {
+            // The light could have been sampled directly at the previous bounce as well
+            let light_pdf = lights.pdf_value(r.origin(), r.direction(), r.time());
+            emitted = emitted * power_heuristic(bsdf_pdf, light_pdf);
+        }
+
+        match result.material.scatter(r, &result) {
+            Some((scatter_result, attenuation)) if depth < max_depth => {
+                let direct = sample_lights(r, &result, world, lights);
+                let scattered_pdf = result.material
+                    .evaluate(r, &result, scatter_result.direction())
+                    .map(|(_, pdf)| pdf);
+                emitted + direct + &attenuation * &color(&scatter_result, world, lights, background, scattered_pdf, depth + 1, max_depth)
+            }
+            _ => emitted
+        }
+    } else {
+        background.value(r)
+    }
+}
+
+/// One light sample with a shadow ray, MIS weighted against sampling the BSDF
+fn sample_lights(r: &Ray, hit: &HitRecord, world: &Arc<dyn Hittable>, lights: &dyn Hittable) -> Vec3 {
+    let direction = match lights.sample_direction(&hit.p, r.time()) {
+        Some(direction) => direction,
+        None => return Vec3::zero(),
+    };
+    let (f_cos, bsdf_pdf) = match hit.material.evaluate(r, hit, &direction) {
+        Some((f_cos, bsdf_pdf)) if bsdf_pdf > 0.0 && bsdf_pdf.is_finite() => (f_cos, bsdf_pdf),
+        _ => return Vec3::zero(),
+    };
+    // A degenerate light sample is dropped, the BSDF sample still finds the light
+    let light_pdf = lights.pdf_value(&hit.p, &direction, r.time());
+    if !(light_pdf > 0.0 && light_pdf.is_finite()) {
+        return Vec3::zero();
+    }
+
+    // The lights alone tell the emitted radiance, the world only has to confirm nothing is in between
+    let shadow_ray = Ray::new(hit.p, direction, r.time());
+    let light_hit = match lights.hit(&shadow_ray, 0.001, f32::MAX) {
+        Some(light_hit) => light_hit,
+        None => return Vec3::zero(),
+    };
+    if world.occluded(&shadow_ray, 0.001, light_hit.t * (1.0 - SHADOW_EPSILON)) {
+        return Vec3::zero();
+    }
+
+    let radiance = light_hit.material.emitted(&shadow_ray, &light_hit);
+    &f_cos * &radiance * (power_heuristic(light_pdf, bsdf_pdf) / light_pdf)
+}
+
+/// Weight of the strategy with density `pdf`. A strategy with a density that is not finite is
+/// skipped by the renderer, so it gets no weight and the other strategy gets all of it
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    if !pdf.is_finite() {
+        return 0.0;
+    }
+    if !other_pdf.is_finite() {
+        return 1.0;
+    }
+    // Squared in f64 so that large densities do not overflow
+    let a = f64::from(pdf) * f64::from(pdf);
+    let b = f64::from(other_pdf) * f64::from(other_pdf);
+    if a + b <= 0.0 {
+        return 0.0;
+    }
+    (a / (a + b)) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene::parse_scene;
+    use std::path::Path;
+
+    #[test]
+    fn power_heuristic_skips_densities_that_are_not_finite() {
+        assert_eq!(power_heuristic(f32::INFINITY, 1.0), 0.0);
+        assert_eq!(power_heuristic(1.0, f32::INFINITY), 1.0);
+        assert_eq!(power_heuristic(f32::NAN, 1.0), 0.0);
+        assert_eq!(power_heuristic(0.0, 0.0), 0.0);
+        assert_eq!(power_heuristic(1e30, 1e30), 0.5);
+        assert!((power_heuristic(1.0, 3.0) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn distant_sphere_light_stays_finite() {
+        // The ground seen by the camera is more than 4000 radii away from the light
+        let source = "\
+            resolution 48 48\n\
+            samples 4\n\
+            background constant color=0,0,0\n\
+            camera look_from=2000,10,0 look_at=2000,0,-20 fov=60\n\
+            material ground lambertian color=0.8,0.8,0.8\n\
+            material lamp diffuse_light color=1,1,1 intensity=1000\n\
+            plane point=0,0,0 normal=0,1,0 material=ground\n\
+            sphere center=0,5,0 radius=0.3 material=lamp\n";
+        let scene = parse_scene(source, Path::new("distant.scene")).unwrap();
+        let image = Renderer::new(RenderSettings::from_scene(&scene)).render(&scene);
+        let bad = image.pixels().iter().filter(|p| !(p.x().is_finite() && p.y().is_finite() && p.z().is_finite())).count();
+        assert_eq!(bad, 0);
+    }
+}
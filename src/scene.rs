@@ -0,0 +1,1009 @@
+//! Text scene description
+//!
+//! One statement per line, `#` starts a comment. A statement is a keyword followed by
+//! positional arguments or `key=value` parameters, vectors are written as `x,y,z` without spaces.
+//! Colors are given in sRGB and converted to linear. Textures and materials are declared with
+//! a name and referenced by it, so they can be shared between objects.
+//!
+//! ```text
+//! resolution 800 400
+//! samples 100
+//! bvh on
+//! background sky
+//! camera look_from=10,2,3 look_at=4,1,1 up=0,1,0 fov=20 aperture=0.1 shutter=0 animation=orbit
+//!
+//! texture green constant color=0.2,0.3,0.1
+//! texture white constant color=0.9,0.9,0.9
+//! texture ground checker odd=green even=white
+//! texture earth image file=earth.jpg wrap=repeat filter=bilinear
+//! texture stone noise pattern=marble scale=4 octaves=7 lacunarity=2 gain=0.5 seed=1
+//!
+//! material ground lambertian texture=ground
+//! material brown lambertian color=0.4,0.2,0.1
+//! material steel metal albedo=0.7,0.6,0.5 roughness=0
+//! material glass dielectric ior=1.5
+//! material lamp diffuse_light color=1,0.9,0.8 intensity=4 two_sided=off
+//!
+//! plane point=0,0,0 normal=0,1,0 material=ground
+//! sphere center=0,0,0 radius=1 scale=2,1,1 rotate=45 translate=0,1,4 material=brown
+//! moving_sphere center0=0,1,0 center1=0,1.5,0 time0=0 time1=1 radius=1 material=brown
+//! triangle a=0,0,0 b=1,0,0 c=0,1,0 material=steel
+//! quad origin=-1,0,-2 u=2,0,0 v=0,2,0 material=lamp flip=on
+//! box min=2,0,-1 max=3,1,0 material=brown
+//! mesh file=teapot.obj material=glass
+//!
+//! object pillar
+//! mesh file=pillar.obj material=brown
+//! sphere center=0,3,0 radius=0.5 material=steel
+//! end
+//! instance pillar translate=2,0,0 rotate=30 axis=0,1,0 scale=0.5
+//! instance pillar translate=-2,0,0 scale=1,2,1 material=glass
+//!
+//! animation spin
+//! key time=0 translate=0,0,0
+//! key time=0.5 translate=0,1,0 rotate=90 axis=0,1,0
+//! key time=1 translate=0,0,0 rotate=180 axis=0,1,0 scale=0.5
+//! end
+//! instance pillar translate=0,0,2 animation=spin
+//!
+//! animation orbit
+//! key time=0
+//! key time=2 rotate=90 axis=0,1,0
+//! end
+//! ```
+//!
+//! Objects with a `diffuse_light` material are also sampled directly as lights, except planes.
+//! A `plane` is infinite, it is tested next to the BVH instead of being part of it.
+//! The front of a `quad` is on the side of u × v, `flip=on` turns spheres, triangles, quads and boxes inside out.
+//! The background is either `sky` (the gradient from the book) or `constant color=r,g,b`.
+//! Lights are one-sided unless `two_sided=on`, their color is scaled by `intensity`.
+//! Camera `focus_dist` defaults to the distance between `look_from` and `look_at`,
+//! mesh and texture files are relative to the scene file and `material` is used for faces without `usemtl`.
+//! Image textures are looked up by the UV of the hit, `wrap` is repeat, clamp or mirror and `filter`
+//! nearest or bilinear. PNG, JPEG and PPM colors are decoded from sRGB, HDR files are used as they are.
+//! Noise textures blend from `dark` to `light` by Perlin noise of the hit point, the `pattern` is noise,
+//! turbulence, marble or wood. The same `seed` (default 0) always gives the same texture.
+//! Shapes between `object NAME` and `end` are not rendered themselves but get their own BVH which
+//! every `instance` of the object shares, `material` replaces the materials of the whole object.
+//! Shapes and instances are scaled by `scale` (one number or x,y,z), rotated by `rotate` degrees
+//! about `axis` (default 0,1,0) and then moved by `translate`.
+//! Keys between `animation NAME` and `end` give the same transform parameters at increasing times,
+//! the scale is interpolated linearly so it cannot change sign from one key to the next.
+//! Shapes and instances with `animation=NAME` are moved by the keys interpolated at the ray time
+//! after their own transform. Set the camera `shutter` to blur the motion. Times are in seconds,
+//! the camera `animation` moves `look_from`, `look_at` and `up` to where they are at the start of each frame.
+
+use crate::vec3::*;
+use crate::camera::{Camera, CameraSettings};
+use crate::hittable::*;
+use crate::texture::*;
+use crate::renderer::Background;
+use crate::aggregate::Aggregate;
+use crate::transform::{Matrix4, Quaternion, Transformed};
+use crate::instance::Instance;
+use crate::animation::{Animated, AnimatedTransform, Keyframe};
+use crate::mesh::{Triangle, TriangleMesh};
+use crate::quad::{BoxShape, Quad};
+use crate::obj::load_obj_meshes;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub struct Scene {
+    pub width: usize,
+    pub height: usize,
+    pub samples: u32,
+    pub camera: CameraSettings,
+    pub objects: Vec<Arc<dyn Hittable>>,
+    /// Emissive objects, also contained in `objects`
+    pub lights: Vec<Arc<dyn Hittable>>,
+    pub use_bvh: bool,
+    pub background: Background,
+    /// Moves the camera between frames of an animation
+    pub camera_animation: Option<Arc<AnimatedTransform>>,
+    /// Spent building the BVHs of meshes and objects while loading
+    pub bvh_build_time: Duration,
+}
+
+impl Scene {
+    pub fn camera(&self) -> Camera {
+        self.camera.build(self.width as f32 / self.height as f32)
+    }
+
+    /// Camera of the frame starting at `time`
+    pub fn camera_at(&self, time: f32) -> CameraSettings {
+        match &self.camera_animation {
+            Some(animation) => {
+                let transform = animation.interpolate(time).to_matrix();
+                CameraSettings {
+                    look_from: transform.transform_point(&self.camera.look_from),
+                    look_at: transform.transform_point(&self.camera.look_at),
+                    up: transform.transform_vector(&self.camera.up),
+                    ..self.camera
+                }
+            },
+            None => self.camera,
+        }
+    }
+
+    /// Puts all objects into one hittable, bounding boxes cover the time interval t0..t1
+    pub fn build_world(&self, t0: f32, t1: f32) -> Arc<dyn Hittable> {
+        Arc::new(self.build_aggregate(t0, t1))
+    }
+
+    /// The world as a concrete type, which can follow moving objects with `Aggregate::update`
+    pub fn build_aggregate(&self, t0: f32, t1: f32) -> Aggregate {
+        Aggregate::new(&self.objects, t0, t1, self.use_bvh)
+    }
+
+    pub fn build_lights(&self) -> HittableList {
+        HittableList { items: self.lights.clone() }
+    }
+}
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub path: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path.display(), line, self.message),
+            None => write!(f, "{}: {}", self.path.display(), self.message),
+        }
+    }
+}
+
+impl Error for SceneError {}
+
+/// `key=value` parameters of one statement, every parameter has to be consumed
+struct Params<'a> {
+    values: HashMap<&'a str, &'a str>,
+    statement: String,
+}
+
+impl<'a> Params<'a> {
+    fn new(statement: String, tokens: &[&'a str]) -> Result<Params<'a>, String> {
+        let mut values = HashMap::new();
+        for token in tokens {
+            let (key, value) = match token.find('=') {
+                Some(i) => (&token[..i], &token[i + 1..]),
+                None => return Err(format!("expected key=value in {}, got '{}'", statement, token)),
+            };
+            if values.insert(key, value).is_some() {
+                return Err(format!("parameter '{}' given twice in {}", key, statement));
+            }
+        }
+
+        Ok(Params {
+            values,
+            statement
+        })
+    }
+
+    fn take_str(&mut self, key: &str) -> Option<&'a str> {
+        self.values.remove(key)
+    }
+
+    fn str(&mut self, key: &str) -> Result<&'a str, String> {
+        self.take_str(key).ok_or_else(|| format!("missing parameter '{}' in {}", key, self.statement))
+    }
+
+    fn opt_f32(&mut self, key: &str) -> Result<Option<f32>, String> {
+        match self.take_str(key) {
+            Some(value) => parse_f32(value)
+                .map(Some)
+                .map_err(|e| format!("parameter '{}' in {}: {}", key, self.statement, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn f32(&mut self, key: &str) -> Result<f32, String> {
+        let value = self.str(key)?;
+        parse_f32(value).map_err(|e| format!("parameter '{}' in {}: {}", key, self.statement, e))
+    }
+
+    fn opt_u32(&mut self, key: &str) -> Result<Option<u32>, String> {
+        match self.take_str(key) {
+            Some(value) => value.parse::<u32>()
+                .map(Some)
+                .map_err(|_| format!("parameter '{}' in {} expects a non-negative integer, got '{}'", key, self.statement, value)),
+            None => Ok(None),
+        }
+    }
+
+    fn opt_u64(&mut self, key: &str) -> Result<Option<u64>, String> {
+        match self.take_str(key) {
+            Some(value) => value.parse::<u64>()
+                .map(Some)
+                .map_err(|_| format!("parameter '{}' in {} expects a non-negative integer, got '{}'", key, self.statement, value)),
+            None => Ok(None),
+        }
+    }
+
+    fn opt_vec3(&mut self, key: &str) -> Result<Option<Vec3>, String> {
+        match self.take_str(key) {
+            Some(value) => parse_vec3(value)
+                .map(Some)
+                .map_err(|e| format!("parameter '{}' in {}: {}", key, self.statement, e)),
+            None => Ok(None),
+        }
+    }
+
+    fn vec3(&mut self, key: &str) -> Result<Vec3, String> {
+        let value = self.str(key)?;
+        parse_vec3(value).map_err(|e| format!("parameter '{}' in {}: {}", key, self.statement, e))
+    }
+
+    fn color(&mut self, key: &str) -> Result<Vec3, String> {
+        Ok(self.vec3(key)?.to_linear())
+    }
+
+    fn opt_bool(&mut self, key: &str) -> Result<Option<bool>, String> {
+        match self.take_str(key) {
+            Some("on") => Ok(Some(true)),
+            Some("off") => Ok(Some(false)),
+            Some(value) => Err(format!("parameter '{}' in {} expects on or off, got '{}'", key, self.statement, value)),
+            None => Ok(None),
+        }
+    }
+
+    /// Scale, rotation about an axis and translation as a keyframe at time 0, None when none of them is given
+    fn opt_trs(&mut self) -> Result<Option<Keyframe>, String> {
+        let scale = match self.take_str("scale") {
+            Some(value) => match (parse_f32(value), parse_vec3(value)) {
+                (Ok(scale), _) => Some(Vec3::new_fill(scale)),
+                (_, Ok(scale)) => Some(scale),
+                _ => return Err(format!("parameter 'scale' in {} expects a number or x,y,z, got '{}'", self.statement, value)),
+            },
+            None => None,
+        };
+        let axis = self.opt_vec3("axis")?;
+        let degrees = self.opt_f32("rotate")?;
+        let offset = self.opt_vec3("translate")?;
+        if scale.is_none() && axis.is_none() && degrees.is_none() && offset.is_none() {
+            return Ok(None);
+        }
+
+        let scale = scale.unwrap_or_else(Vec3::one);
+        let axis = axis.unwrap_or_else(Vec3::up);
+        let degrees = degrees.unwrap_or(0.0);
+        let offset = offset.unwrap_or_else(Vec3::zero);
+
+        if scale.v().contains(&0.0) {
+            return Err(format!("parameter 'scale' in {} cannot be zero", self.statement));
+        }
+        if axis.length_sqr() == 0.0 {
+            return Err(format!("parameter 'axis' in {} cannot be zero", self.statement));
+        }
+        let key = Keyframe::new(0.0, offset, Quaternion::from_axis_angle(axis, degrees), scale);
+        // Scales too small for f32 collapse space just like a zero scale
+        if key.to_matrix().inverse().is_none() {
+            return Err(format!("transform in {} is not invertible, parameter 'scale' is too small", self.statement));
+        }
+        Ok(Some(key))
+    }
+
+    /// Scale, then rotation about an axis, then translation, None when none of them is given
+    fn opt_transform(&mut self) -> Result<Option<Matrix4>, String> {
+        Ok(self.opt_trs()?.map(|key| key.to_matrix()))
+    }
+
+    /// Fails on parameters nobody asked for, most likely a typo
+    fn finish(self) -> Result<(), String> {
+        let mut unknown: Vec<&str> = self.values.keys().cloned().collect();
+        if unknown.is_empty() {
+            return Ok(());
+        }
+        unknown.sort_unstable();
+        Err(format!("unknown parameter(s) {} in {}", unknown.join(", "), self.statement))
+    }
+}
+
+fn flipped(object: Arc<dyn Hittable>, flip: Option<bool>) -> Arc<dyn Hittable> {
+    match flip {
+        Some(true) => Arc::new(FlipNormals::new(object)),
+        _ => object,
+    }
+}
+
+/// Applies the static transform first and the animation on top of it
+fn place(object: Arc<dyn Hittable>, transform: Option<Matrix4>, animation: Option<Arc<AnimatedTransform>>) -> Arc<dyn Hittable> {
+    let object: Arc<dyn Hittable> = match transform {
+        Some(transform) => Arc::new(Transformed::new(object, transform)),
+        None => object,
+    };
+    match animation {
+        Some(animation) => Arc::new(Animated::new(object, animation)),
+        None => object,
+    }
+}
+
+fn parse_f32(value: &str) -> Result<f32, String> {
+    value.parse::<f32>().map_err(|_| format!("'{}' is not a number", value))
+}
+
+fn parse_vec3(value: &str) -> Result<Vec3, String> {
+    let parts: Vec<&str> = value.split(',').collect();
+    if parts.len() != 3 {
+        return Err(format!("expected x,y,z but got '{}'", value));
+    }
+    Ok(Vec3::new(parse_f32(parts[0])?, parse_f32(parts[1])?, parse_f32(parts[2])?))
+}
+
+fn parse_count(value: &str, what: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(count) if count > 0 => Ok(count),
+        _ => Err(format!("{} has to be a positive integer, got '{}'", what, value)),
+    }
+}
+
+struct CameraParams {
+    look_from: Vec3,
+    look_at: Vec3,
+    up: Vec3,
+    fov: f32,
+    aperture: f32,
+    focus_dist: Option<f32>,
+    shutter_time: f32,
+    /// Looked up at the end so the animation can be defined after the camera
+    animation: Option<String>,
+}
+
+struct SceneParser<'a> {
+    dir: &'a Path,
+    width: usize,
+    height: usize,
+    samples: u32,
+    use_bvh: bool,
+    background: Background,
+    camera: Option<CameraParams>,
+    textures: HashMap<String, Arc<dyn Texture>>,
+    materials: HashMap<String, Arc<dyn Material>>,
+    objects: Vec<Arc<dyn Hittable>>,
+    lights: Vec<Arc<dyn Hittable>>,
+    /// Finished `object` blocks by name
+    prototypes: HashMap<String, Prototype>,
+    /// Finished `animation` blocks by name
+    animations: HashMap<String, Arc<AnimatedTransform>>,
+    /// The `object` or `animation` block being parsed
+    block: Option<Block>,
+    /// Times anything moves in, bounding boxes of object BVHs cover all of it
+    time_range: (f32, f32),
+    bvh_build_time: Duration,
+}
+
+struct Prototype {
+    geometry: Arc<dyn Hittable>,
+    emissive: bool,
+}
+
+enum Block {
+    /// Shapes go here instead of the scene
+    Object {
+        name: String,
+        shapes: Vec<Arc<dyn Hittable>>,
+        emissive: bool,
+    },
+    Animation {
+        name: String,
+        keys: Vec<Keyframe>,
+    },
+}
+
+impl Block {
+    fn name(&self) -> &str {
+        match self {
+            Block::Object { name, .. } | Block::Animation { name, .. } => name,
+        }
+    }
+}
+
+impl<'a> SceneParser<'a> {
+    fn add_object(&mut self, object: Arc<dyn Hittable>, emissive: bool) {
+        if let Some(Block::Object { shapes, emissive: block_emissive, .. }) = &mut self.block {
+            shapes.push(object);
+            *block_emissive |= emissive;
+            return;
+        }
+
+        if emissive {
+            self.lights.push(object.clone());
+        }
+        self.objects.push(object);
+    }
+
+    fn texture(&self, name: &str) -> Result<Arc<dyn Texture>, String> {
+        self.textures.get(name).cloned().ok_or_else(|| format!("unknown texture '{}'", name))
+    }
+
+    fn material(&self, name: &str) -> Result<Arc<dyn Material>, String> {
+        self.materials.get(name).cloned().ok_or_else(|| format!("unknown material '{}'", name))
+    }
+
+    fn extend_time_range(&mut self, t0: f32, t1: f32) {
+        self.time_range = (self.time_range.0.min(t0), self.time_range.1.max(t1));
+    }
+
+    fn animation(&self, params: &mut Params) -> Result<Option<Arc<AnimatedTransform>>, String> {
+        match params.take_str("animation") {
+            Some(name) => self.animations.get(name).cloned()
+                .map(Some)
+                .ok_or_else(|| format!("unknown animation '{}'", name)),
+            None => Ok(None),
+        }
+    }
+
+    fn statement(&mut self, tokens: &[&str]) -> Result<(), String> {
+        let keyword = tokens[0];
+        let args = &tokens[1..];
+        match &self.block {
+            Some(Block::Object { name, .. }) if !["sphere", "plane", "moving_sphere", "triangle", "quad", "box", "mesh", "instance", "end"].contains(&keyword) => {
+                return Err(format!("only shapes can be used inside object '{}', got '{}'", name, keyword));
+            },
+            Some(Block::Animation { name, .. }) if !["key", "end"].contains(&keyword) => {
+                return Err(format!("only keys can be used inside animation '{}', got '{}'", name, keyword));
+            },
+            _ => {},
+        }
+
+        match keyword {
+            "resolution" => {
+                if args.len() != 2 {
+                    return Err(format!("resolution expects width and height, got {} values", args.len()));
+                }
+                self.width = parse_count(args[0], "width")?;
+                self.height = parse_count(args[1], "height")?;
+            },
+            "samples" => {
+                if args.len() != 1 {
+                    return Err("samples expects one value".to_string());
+                }
+                let samples = parse_count(args[0], "samples")?;
+                self.samples = u32::try_from(samples)
+                    .map_err(|_| format!("samples can be at most {}, got '{}'", u32::MAX, args[0]))?;
+            },
+            "bvh" => {
+                self.use_bvh = match args {
+                    ["on"] => true,
+                    ["off"] => false,
+                    _ => return Err("bvh expects 'on' or 'off'".to_string()),
+                };
+            },
+            "background" => {
+                self.background = match args {
+                    ["sky"] => Background::Sky,
+                    ["constant", rest @ ..] => {
+                        let mut params = Params::new("background".to_string(), rest)?;
+                        let color = params.color("color")?;
+                        params.finish()?;
+                        Background::Color(color)
+                    },
+                    _ => return Err("background expects 'sky' or 'constant color=r,g,b'".to_string()),
+                };
+            },
+            "camera" => {
+                let mut params = Params::new("camera".to_string(), args)?;
+                self.camera = Some(CameraParams {
+                    look_from: params.vec3("look_from")?,
+                    look_at: params.vec3("look_at")?,
+                    up: params.opt_vec3("up")?.unwrap_or_else(Vec3::up),
+                    fov: params.f32("fov")?,
+                    aperture: params.opt_f32("aperture")?.unwrap_or(0.0),
+                    focus_dist: params.opt_f32("focus_dist")?,
+                    shutter_time: params.opt_f32("shutter")?.unwrap_or(0.0),
+                    animation: params.take_str("animation").map(str::to_string),
+                });
+                params.finish()?;
+            },
+            "texture" => {
+                let (name, kind) = match args {
+                    [name, kind, ..] => (name.to_string(), *kind),
+                    _ => return Err("texture expects a name and a type".to_string()),
+                };
+                if self.textures.contains_key(&name) {
+                    return Err(format!("texture '{}' is already defined", name));
+                }
+                let mut params = Params::new(format!("texture '{}'", name), &args[2..])?;
+                let texture: Arc<dyn Texture> = match kind {
+                    "constant" => Arc::new(ConstantTexture::new(params.color("color")?)),
+                    "checker" => Arc::new(CheckerTexture::new(
+                        self.texture(params.str("odd")?)?,
+                        self.texture(params.str("even")?)?
+                    )),
+                    "image" => {
+                        let file = self.dir.join(params.str("file")?);
+                        let wrap = match params.take_str("wrap").unwrap_or("repeat") {
+                            "repeat" => WrapMode::Repeat,
+                            "clamp" => WrapMode::Clamp,
+                            "mirror" => WrapMode::Mirror,
+                            value => return Err(format!("parameter 'wrap' in texture '{}' expects repeat, clamp or mirror, got '{}'", name, value)),
+                        };
+                        let filter = match params.take_str("filter").unwrap_or("bilinear") {
+                            "nearest" => Filter::Nearest,
+                            "bilinear" => Filter::Bilinear,
+                            value => return Err(format!("parameter 'filter' in texture '{}' expects nearest or bilinear, got '{}'", name, value)),
+                        };
+                        Arc::new(ImageTexture::load(&file, wrap, filter)?)
+                    },
+                    "noise" => {
+                        let pattern = match params.take_str("pattern").unwrap_or("noise") {
+                            "noise" => NoisePattern::Noise,
+                            "turbulence" => NoisePattern::Turbulence,
+                            "marble" => NoisePattern::Marble,
+                            "wood" => NoisePattern::Wood,
+                            value => return Err(format!("parameter 'pattern' in texture '{}' expects noise, turbulence, marble or wood, got '{}'", name, value)),
+                        };
+                        let (dark, light) = pattern.default_colors();
+                        Arc::new(NoiseTexture::new(
+                            params.opt_u64("seed")?.unwrap_or(0),
+                            pattern,
+                            params.opt_f32("scale")?.unwrap_or(1.0),
+                            params.opt_u32("octaves")?.unwrap_or(7),
+                            params.opt_f32("lacunarity")?.unwrap_or(2.0),
+                            params.opt_f32("gain")?.unwrap_or(0.5),
+                            params.opt_vec3("dark")?.map_or(dark, Vec3::to_linear),
+                            params.opt_vec3("light")?.map_or(light, Vec3::to_linear)
+                        ))
+                    },
+                    _ => return Err(format!("unknown texture type '{}', expected constant, checker, image or noise", kind)),
+                };
+                params.finish()?;
+                self.textures.insert(name, texture);
+            },
+            "material" => {
+                let (name, kind) = match args {
+                    [name, kind, ..] => (name.to_string(), *kind),
+                    _ => return Err("material expects a name and a type".to_string()),
+                };
+                if self.materials.contains_key(&name) {
+                    return Err(format!("material '{}' is already defined", name));
+                }
+                let mut params = Params::new(format!("material '{}'", name), &args[2..])?;
+                let material: Arc<dyn Material> = match kind {
+                    "lambertian" => {
+                        let texture = match (params.take_str("texture"), params.opt_vec3("color")?) {
+                            (Some(texture), None) => self.texture(texture)?,
+                            (None, Some(color)) => Arc::new(ConstantTexture::new(color.to_linear())),
+                            _ => return Err(format!("lambertian material '{}' needs either texture or color", name)),
+                        };
+                        Arc::new(Lambertian::new(texture))
+                    },
+                    "metal" => Arc::new(Metal::new(params.color("albedo")?, params.opt_f32("roughness")?.unwrap_or(0.0))),
+                    "dielectric" => Arc::new(Dielectric::new(params.f32("ior")?)),
+                    "diffuse_light" => {
+                        let intensity = params.opt_f32("intensity")?;
+                        let texture = match (params.take_str("texture"), params.opt_vec3("color")?, intensity) {
+                            (Some(texture), None, None) => self.texture(texture)?,
+                            (None, Some(color), intensity) => Arc::new(ConstantTexture::new(intensity.unwrap_or(1.0) * color.to_linear())),
+                            (Some(_), None, Some(_)) => return Err(format!("diffuse_light material '{}' can only use intensity together with color", name)),
+                            _ => return Err(format!("diffuse_light material '{}' needs either texture or color", name)),
+                        };
+                        Arc::new(DiffuseLight::new(texture, params.opt_bool("two_sided")?.unwrap_or(false)))
+                    },
+                    _ => return Err(format!("unknown material type '{}', expected lambertian, metal, dielectric or diffuse_light", kind)),
+                };
+                params.finish()?;
+                self.materials.insert(name, material);
+            },
+            "sphere" => {
+                let mut params = Params::new("sphere".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let sphere = Arc::new(Sphere::new(
+                    params.vec3("center")?,
+                    params.f32("radius")?,
+                    material.clone()
+                ));
+                let sphere = flipped(sphere, params.opt_bool("flip")?);
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                self.add_object(place(sphere, transform, animation), material.is_emissive());
+            },
+            "plane" => {
+                let mut params = Params::new("plane".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let normal = params.vec3("normal")?;
+                if normal.length_sqr() == 0.0 {
+                    return Err("parameter 'normal' in plane cannot be zero".to_string());
+                }
+                let plane = Arc::new(Plane::new(params.vec3("point")?, normal, material.clone()));
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                // Infinite planes cannot be sampled, glowing ones are only found by bounces
+                self.add_object(place(plane, transform, animation), false);
+            },
+            "moving_sphere" => {
+                let mut params = Params::new("moving_sphere".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let (time0, time1) = (params.f32("time0")?, params.f32("time1")?);
+                let sphere = Arc::new(MovingSphere::new(
+                    params.vec3("center0")?,
+                    params.vec3("center1")?,
+                    time0,
+                    time1,
+                    params.f32("radius")?,
+                    material.clone()
+                ));
+                self.extend_time_range(time0.min(time1), time0.max(time1));
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                self.add_object(place(sphere, transform, animation), material.is_emissive());
+            },
+            "triangle" => {
+                let mut params = Params::new("triangle".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let triangle = Arc::new(Triangle::new(
+                    params.vec3("a")?,
+                    params.vec3("b")?,
+                    params.vec3("c")?,
+                    material.clone()
+                ));
+                let triangle = flipped(triangle, params.opt_bool("flip")?);
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                self.add_object(place(triangle, transform, animation), material.is_emissive());
+            },
+            "quad" => {
+                let mut params = Params::new("quad".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let (u, v) = (params.vec3("u")?, params.vec3("v")?);
+                if u.cross(&v).length_sqr() == 0.0 {
+                    return Err("parameters 'u' and 'v' in quad have to span an area".to_string());
+                }
+                let quad = Arc::new(Quad::new(params.vec3("origin")?, u, v, material.clone()));
+                let quad = flipped(quad, params.opt_bool("flip")?);
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                self.add_object(place(quad, transform, animation), material.is_emissive());
+            },
+            "box" => {
+                let mut params = Params::new("box".to_string(), args)?;
+                let material = self.material(params.str("material")?)?;
+                let (min, max) = (params.vec3("min")?, params.vec3("max")?);
+                if (max - min).v().contains(&0.0) {
+                    return Err("parameters 'min' and 'max' in box have to differ on every axis".to_string());
+                }
+                let shape = Arc::new(BoxShape::new(min, max, material.clone()));
+                let shape = flipped(shape, params.opt_bool("flip")?);
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                self.add_object(place(shape, transform, animation), material.is_emissive());
+            },
+            "mesh" => {
+                let mut params = Params::new("mesh".to_string(), args)?;
+                let file = self.dir.join(params.str("file")?);
+                let material = self.material(params.str("material")?)?;
+                let transform = params.opt_transform()?;
+                let animation = self.animation(&mut params)?;
+                params.finish()?;
+                let meshes = load_obj_meshes(&file, material).map_err(|e| e.to_string())?;
+                for obj_mesh in meshes {
+                    let emissive = obj_mesh.mesh.material().is_emissive();
+                    let build_start = Instant::now();
+                    let mesh = Arc::new(TriangleMesh::new(obj_mesh.mesh));
+                    self.bvh_build_time += build_start.elapsed();
+                    self.add_object(place(mesh, transform, animation.clone()), emissive);
+                }
+            },
+            "object" => {
+                let name = match args {
+                    [name] => name.to_string(),
+                    _ => return Err("object expects just a name".to_string()),
+                };
+                if self.prototypes.contains_key(&name) {
+                    return Err(format!("object '{}' is already defined", name));
+                }
+                self.block = Some(Block::Object {
+                    name,
+                    shapes: vec![],
+                    emissive: false
+                });
+            },
+            "animation" => {
+                let name = match args {
+                    [name] => name.to_string(),
+                    _ => return Err("animation expects just a name".to_string()),
+                };
+                if self.animations.contains_key(&name) {
+                    return Err(format!("animation '{}' is already defined", name));
+                }
+                self.block = Some(Block::Animation {
+                    name,
+                    keys: vec![]
+                });
+            },
+            "key" => {
+                let mut params = Params::new("key".to_string(), args)?;
+                let time = params.f32("time")?;
+                let key = params.opt_trs()?.unwrap_or_else(|| Keyframe::new(0.0, Vec3::zero(), Quaternion::identity(), Vec3::one()));
+                params.finish()?;
+                let keys = match &mut self.block {
+                    Some(Block::Animation { keys, .. }) => keys,
+                    _ => return Err("key outside of an animation".to_string()),
+                };
+                if let Some(last) = keys.last() {
+                    if time <= last.time {
+                        return Err(format!("key time {} has to be after the previous key at {}", time, last.time));
+                    }
+                    // The scale is interpolated linearly and would pass through zero
+                    if (0..3).any(|i| last.scale.v()[i].signum() != key.scale.v()[i].signum()) {
+                        return Err(format!("scale of the key at time {} cannot change sign from the previous key at {}", time, last.time));
+                    }
+                }
+                keys.push(Keyframe { time, ..key });
+            },
+            "end" => {
+                if !args.is_empty() {
+                    return Err("end expects no arguments".to_string());
+                }
+                match self.block.take() {
+                    Some(Block::Object { name, shapes, emissive }) => {
+                        // Bottom level BVH shared by every frame, motion stops outside the time range
+                        // so bounding boxes over the range cover any time
+                        let (t0, t1) = self.time_range;
+                        let build_start = Instant::now();
+                        let geometry: Arc<dyn Hittable> = match shapes.len() {
+                            0 => return Err(format!("object '{}' has no shapes", name)),
+                            1 => shapes[0].clone(),
+                            _ => Arc::new(Aggregate::new(&shapes, t0, t1, true)),
+                        };
+                        self.bvh_build_time += build_start.elapsed();
+                        self.prototypes.insert(name, Prototype {
+                            geometry,
+                            emissive
+                        });
+                    },
+                    Some(Block::Animation { name, keys }) => {
+                        let (first, last) = match (keys.first(), keys.last()) {
+                            (Some(first), Some(last)) => (first.time, last.time),
+                            _ => return Err(format!("animation '{}' has no keys", name)),
+                        };
+                        self.extend_time_range(first, last);
+                        self.animations.insert(name, Arc::new(AnimatedTransform::new(keys)));
+                    },
+                    None => return Err("end without object or animation".to_string()),
+                }
+            },
+            "instance" => {
+                let name = match args.first() {
+                    Some(name) => *name,
+                    None => return Err("instance expects an object name".to_string()),
+                };
+                let mut params = Params::new(format!("instance of '{}'", name), &args[1..])?;
+                let transform = params.opt_transform()?.unwrap_or_else(Matrix4::identity);
+                let animation = self.animation(&mut params)?;
+                let material = match params.take_str("material") {
+                    Some(material) => Some(self.material(material)?),
+                    None => None,
+                };
+                params.finish()?;
+
+                let prototype = self.prototypes.get(name).ok_or_else(|| format!("unknown object '{}'", name))?;
+                let emissive = match &material {
+                    Some(material) => material.is_emissive(),
+                    None => prototype.emissive,
+                };
+                let instance = Instance::new(prototype.geometry.clone(), transform, material);
+                self.add_object(place(Arc::new(instance), None, animation), emissive);
+            },
+            _ => return Err(format!("unknown statement '{}'", keyword)),
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Scene, String> {
+        if let Some(block) = &self.block {
+            return Err(format!("'{}' is missing its end", block.name()));
+        }
+        let camera = self.camera.ok_or_else(|| "scene has no camera".to_string())?;
+        let focus_dist = camera.focus_dist.unwrap_or_else(|| (camera.look_from - camera.look_at).length());
+        let camera_animation = match &camera.animation {
+            Some(name) => Some(self.animations.get(name).cloned().ok_or_else(|| format!("unknown camera animation '{}'", name))?),
+            None => None,
+        };
+
+        Ok(Scene {
+            width: self.width,
+            height: self.height,
+            samples: self.samples,
+            camera: CameraSettings {
+                look_from: camera.look_from,
+                look_at: camera.look_at,
+                up: camera.up,
+                fov: camera.fov,
+                aperture: camera.aperture,
+                focus_dist,
+                shutter_time: camera.shutter_time
+            },
+            objects: self.objects,
+            lights: self.lights,
+            use_bvh: self.use_bvh,
+            background: self.background,
+            camera_animation,
+            bvh_build_time: self.bvh_build_time
+        })
+    }
+}
+
+/// Parses a scene from text, `path` is used for error messages and relative mesh files
+pub fn parse_scene(source: &str, path: &Path) -> Result<Scene, SceneError> {
+    let mut parser = SceneParser {
+        dir: path.parent().unwrap_or_else(|| Path::new("")),
+        width: 800,
+        height: 400,
+        samples: 100,
+        use_bvh: true,
+        background: Background::Sky,
+        camera: None,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+        objects: vec![],
+        lights: vec![],
+        prototypes: HashMap::new(),
+        animations: HashMap::new(),
+        block: None,
+        time_range: (0.0, 1.0),
+        bvh_build_time: Duration::default(),
+    };
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+
+        parser.statement(&tokens).map_err(|message| SceneError {
+            path: path.to_path_buf(),
+            line: Some(line_idx + 1),
+            message
+        })?;
+    }
+
+    parser.finish().map_err(|message| SceneError {
+        path: path.to_path_buf(),
+        line: None,
+        message
+    })
+}
+
+pub fn load_scene(path: &Path) -> Result<Scene, SceneError> {
+    let source = fs::read_to_string(path).map_err(|e| SceneError {
+        path: path.to_path_buf(),
+        line: None,
+        message: e.to_string()
+    })?;
+    parse_scene(&source, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "camera look_from=0,0,5 look_at=0,0,0 fov=40\nmaterial white lambertian color=1,1,1\n";
+
+    /// Parses `body` after a camera and a material named white, the body starts on line 3
+    fn parse(body: &str) -> Result<Scene, SceneError> {
+        parse_scene(&format!("{}{}", HEADER, body), Path::new("test.scene"))
+    }
+
+    fn parse_error(body: &str) -> SceneError {
+        parse(body).err().unwrap()
+    }
+
+    #[test]
+    fn parses_a_valid_scene() {
+        let scene = parse("resolution 64 32\nsamples 4\nsphere center=0,0,0 radius=1 material=white\n").unwrap();
+        assert_eq!((scene.width, scene.height, scene.samples), (64, 32, 4));
+        assert_eq!(scene.objects.len(), 1);
+    }
+
+    #[test]
+    fn reports_the_path_and_line() {
+        let error = parse_error("\n# comment\nspheres center=0,0,0\n");
+        assert_eq!(error.line, Some(5));
+        assert_eq!(error.to_string(), "test.scene:5: unknown statement 'spheres'");
+    }
+
+    #[test]
+    fn rejects_unknown_types() {
+        let error = parse_error("texture t marbled\n");
+        assert!(error.message.contains("unknown texture type 'marbled'"), "{}", error);
+
+        let error = parse_error("material m glossy\n");
+        assert!(error.message.contains("unknown material type 'glossy'"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_bad_parameters() {
+        let error = parse_error("sphere center=0,0,0 radius=big material=white\n");
+        assert_eq!(error.message, "parameter 'radius' in sphere: 'big' is not a number");
+
+        let error = parse_error("sphere center=0,0 radius=1 material=white\n");
+        assert!(error.message.contains("expected x,y,z but got '0,0'"), "{}", error);
+
+        let error = parse_error("sphere center=0,0,0 radius=1 material=white colour=1,0,0\n");
+        assert!(error.message.contains("unknown parameter(s) colour in sphere"), "{}", error);
+
+        let error = parse_error("sphere center=0,0,0 material=white\n");
+        assert!(error.message.contains("missing parameter 'radius' in sphere"), "{}", error);
+
+        let error = parse_error("sphere center=0,0,0 radius=1 radius=2 material=white\n");
+        assert!(error.message.contains("parameter 'radius' given twice in sphere"), "{}", error);
+
+        let error = parse_error("sphere center=0,0,0 radius=1 material=white flip=yes\n");
+        assert!(error.message.contains("parameter 'flip' in sphere expects on or off, got 'yes'"), "{}", error);
+
+        let error = parse_error("sphere center=0,0,0 radius 1 material=white\n");
+        assert!(error.message.contains("expected key=value in sphere, got 'radius'"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unknown_references() {
+        let error = parse_error("sphere center=0,0,0 radius=1 material=red\n");
+        assert_eq!(error.line, Some(3));
+        assert!(error.message.contains("unknown material 'red'"), "{}", error);
+
+        let error = parse_error("material m lambertian texture=wood\n");
+        assert!(error.message.contains("unknown texture 'wood'"), "{}", error);
+
+        let error = parse_error("instance crate\n");
+        assert!(error.message.contains("unknown object 'crate'"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_unterminated_blocks() {
+        let error = parse_error("object lamp\nsphere center=0,0,0 radius=1 material=white\n");
+        assert_eq!(error.line, None);
+        assert_eq!(error.to_string(), "test.scene: 'lamp' is missing its end");
+
+        let error = parse_error("animation spin\nkey time=0\n");
+        assert!(error.message.contains("'spin' is missing its end"), "{}", error);
+
+        let error = parse_error("object a\nobject b\n");
+        assert_eq!(error.line, Some(4));
+        assert!(error.message.contains("only shapes can be used inside object 'a', got 'object'"), "{}", error);
+
+        let error = parse_error("animation spin\nobject a\n");
+        assert!(error.message.contains("only keys can be used inside animation 'spin', got 'object'"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_sample_counts_out_of_range() {
+        let scene = parse("samples 4294967295\n").unwrap();
+        assert_eq!(scene.samples, u32::MAX);
+
+        let error = parse_error("samples 4294967296\n");
+        assert!(error.message.contains("samples can be at most 4294967295, got '4294967296'"), "{}", error);
+
+        let error = parse_error("samples 0\n");
+        assert!(error.message.contains("samples has to be a positive integer, got '0'"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_scales_changing_sign_between_keys() {
+        let error = parse_error("animation flip\nkey time=0 scale=1\nkey time=1 scale=-1,1,1\nend\n");
+        assert_eq!(error.line, Some(5));
+        assert!(error.message.contains("scale of the key at time 1 cannot change sign"), "{}", error);
+
+        parse("animation mirrored\nkey time=0 scale=-1,1,1\nkey time=1 scale=-2,1,1\nend\n").unwrap();
+    }
+}
@@ -1,4 +1,7 @@
 use crate::vec3::*;
+use crate::perlin::Perlin;
+use std::path::Path;
+use std::sync::Arc;
 
 pub trait Texture : Sync + Send {
     fn value(&self, u: f32, v: f32, point: Vec3) -> Vec3;
@@ -23,12 +26,12 @@ impl Texture for ConstantTexture {
 }
 
 pub struct CheckerTexture {
-    odd_texture: Box<dyn Texture>,
-    even_texture: Box<dyn Texture>,
+    odd_texture: Arc<dyn Texture>,
+    even_texture: Arc<dyn Texture>,
 }
 
 impl CheckerTexture {
-    pub fn new(odd_texture: Box<dyn Texture>, even_texture: Box<dyn Texture>) -> CheckerTexture {
+    pub fn new(odd_texture: Arc<dyn Texture>, even_texture: Arc<dyn Texture>) -> CheckerTexture {
         CheckerTexture {
             odd_texture,
             even_texture
@@ -46,3 +49,349 @@ impl Texture for CheckerTexture {
         }
     }
 }
+
+/// Shape the noise of a `NoiseTexture` is turned into
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum NoisePattern {
+    /// Smooth noise mapped to 0..1
+    Noise,
+    /// Turbulence as it is, with sharp creases where the noise crosses zero
+    Turbulence,
+    /// Sine stripes along z bent by turbulence
+    Marble,
+    /// Rings around the y axis bent by turbulence
+    Wood,
+}
+
+impl NoisePattern {
+    /// Colors the pattern blends between by default, in linear space
+    pub fn default_colors(self) -> (Vec3, Vec3) {
+        match self {
+            NoisePattern::Noise | NoisePattern::Turbulence => (Vec3::zero(), Vec3::one()),
+            NoisePattern::Marble => (Vec3::new(0.3, 0.3, 0.33).to_linear(), Vec3::new(0.95, 0.94, 0.92).to_linear()),
+            NoisePattern::Wood => (Vec3::new(0.4, 0.22, 0.1).to_linear(), Vec3::new(0.78, 0.55, 0.32).to_linear()),
+        }
+    }
+}
+
+/// Solid texture blending two colors by Perlin noise at the hit point, the UV is not used
+pub struct NoiseTexture {
+    perlin: Perlin,
+    pattern: NoisePattern,
+    /// Frequency of the pattern, features are about 1 / scale apart
+    scale: f32,
+    octaves: u32,
+    lacunarity: f32,
+    gain: f32,
+    dark: Vec3,
+    light: Vec3,
+}
+
+impl NoiseTexture {
+    /// `octaves`, `lacunarity` and `gain` shape the turbulence which all patterns except `Noise` use
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(seed: u64, pattern: NoisePattern, scale: f32, octaves: u32, lacunarity: f32, gain: f32, dark: Vec3, light: Vec3) -> NoiseTexture {
+        NoiseTexture {
+            perlin: Perlin::new(seed),
+            pattern,
+            scale,
+            octaves,
+            lacunarity,
+            gain,
+            dark,
+            light
+        }
+    }
+
+    /// White marble with gray veins
+    pub fn marble(seed: u64, scale: f32) -> NoiseTexture {
+        let (dark, light) = NoisePattern::Marble.default_colors();
+        NoiseTexture::new(seed, NoisePattern::Marble, scale, 7, 2.0, 0.5, dark, light)
+    }
+
+    /// Brown growth rings
+    pub fn wood(seed: u64, scale: f32) -> NoiseTexture {
+        let (dark, light) = NoisePattern::Wood.default_colors();
+        NoiseTexture::new(seed, NoisePattern::Wood, scale, 4, 2.0, 0.5, dark, light)
+    }
+
+    /// Blend factor from dark to light at `point`
+    fn pattern_value(&self, point: &Vec3) -> f32 {
+        let p = self.scale * point;
+        let t = match self.pattern {
+            NoisePattern::Noise => 0.5 * (1.0 + self.perlin.noise(&p)),
+            NoisePattern::Turbulence => self.perlin.turbulence(&p, self.octaves, self.lacunarity, self.gain),
+            // The bending follows the unscaled point so that `scale` only sets how dense the stripes are
+            NoisePattern::Marble => {
+                let bend = self.perlin.turbulence(point, self.octaves, self.lacunarity, self.gain);
+                0.5 * (1.0 + (p.z() + 10.0 * bend).sin())
+            },
+            NoisePattern::Wood => {
+                let bend = self.perlin.turbulence(point, self.octaves, self.lacunarity, self.gain);
+                let rings = (sqr(p.x()) + sqr(p.z())).sqrt() + 2.0 * bend;
+                rings - rings.floor()
+            },
+        };
+        clamp(t, 0.0, 1.0)
+    }
+}
+
+impl Texture for NoiseTexture {
+    fn value(&self, _u: f32, _v: f32, point: Vec3) -> Vec3 {
+        let t = self.pattern_value(&point);
+        (1.0 - t) * self.dark + t * self.light
+    }
+}
+
+/// How texture coordinates outside of 0..1 are mapped back onto the image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    /// Repeats with every other copy flipped so the edges meet seamlessly
+    Mirror,
+}
+
+impl WrapMode {
+    fn apply(self, i: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match self {
+            WrapMode::Repeat => i.rem_euclid(size),
+            WrapMode::Clamp => i.clamp(0, size - 1),
+            WrapMode::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size { i } else { 2 * size - 1 - i }
+            },
+        };
+        i as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+/// Image looked up by the UV of the hit, v goes up from the bottom row
+pub struct ImageTexture {
+    width: usize,
+    height: usize,
+    /// Linear colors, rows go from the top of the image
+    pixels: Vec<Vec3>,
+    wrap: WrapMode,
+    filter: Filter,
+}
+
+impl ImageTexture {
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>, wrap: WrapMode, filter: Filter) -> ImageTexture {
+        assert!(width > 0 && height > 0, "Image texture can not be empty");
+        assert_eq!(pixels.len(), width * height, "Image texture needs exactly width * height pixels");
+        ImageTexture {
+            width,
+            height,
+            pixels,
+            wrap,
+            filter
+        }
+    }
+
+    /// Loads a PNG, JPEG, PPM or HDR file. 8 and 16 bit images are decoded from sRGB, HDR files are linear already
+    pub fn load(path: &Path, wrap: WrapMode, filter: Filter) -> Result<ImageTexture, String> {
+        let error = |e: image::ImageError| format!("cannot load texture {}: {}", path.display(), e);
+        let reader = image::io::Reader::open(path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| error(e.into()))?;
+        if reader.format() == Some(image::ImageFormat::Hdr) {
+            // The generic decoding of HDR files tone maps them down to 8 bits
+            let decoder = image::codecs::hdr::HdrDecoder::new(reader.into_inner()).map_err(error)?;
+            let metadata = decoder.metadata();
+            let pixels = decoder.read_image_hdr().map_err(error)?
+                .iter()
+                .map(|p| Vec3::new(p[0], p[1], p[2]))
+                .collect();
+            return Ok(ImageTexture::new(metadata.width as usize, metadata.height as usize, pixels, wrap, filter));
+        }
+
+        let image = reader.decode().map_err(error)?;
+        let linear = matches!(image.color(), image::ColorType::Rgb32F | image::ColorType::Rgba32F);
+        let image = image.to_rgb32f();
+        let pixels = image.pixels()
+            .map(|p| {
+                let color = Vec3::new(p[0], p[1], p[2]);
+                if linear { color } else { color.to_linear() }
+            })
+            .collect();
+        Ok(ImageTexture::new(image.width() as usize, image.height() as usize, pixels, wrap, filter))
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    fn texel(&self, x: i64, y: i64) -> Vec3 {
+        let x = self.wrap.apply(x, self.width);
+        let y = self.wrap.apply(y, self.height);
+        self.pixels[y * self.width + x]
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _point: Vec3) -> Vec3 {
+        // Continuous pixel coordinates from the top left corner
+        let x = u * self.width as f32;
+        let y = (1.0 - v) * self.height as f32;
+        match self.filter {
+            Filter::Nearest => self.texel(x.floor() as i64, y.floor() as i64),
+            Filter::Bilinear => {
+                // Pixel centers are at half coordinates
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as i64, y0 as i64);
+                let top = (1.0 - tx) * self.texel(x0, y0) + tx * self.texel(x0 + 1, y0);
+                let bottom = (1.0 - tx) * self.texel(x0, y0 + 1) + tx * self.texel(x0 + 1, y0 + 1);
+                (1.0 - ty) * top + ty * bottom
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::write_hdr;
+    use crate::renderer::Framebuffer;
+    use std::fs;
+
+    /// 3x2 texture where every texel has its own gray level, x + 3 * y from the top left
+    fn numbered(wrap: WrapMode, filter: Filter) -> ImageTexture {
+        let pixels = (0..6).map(|i| Vec3::new(i as f32, i as f32, i as f32)).collect();
+        ImageTexture::new(3, 2, pixels, wrap, filter)
+    }
+
+    /// UV of the center of the texel in column x, row y from the top
+    fn center(x: f32, y: f32) -> (f32, f32) {
+        ((x + 0.5) / 3.0, 1.0 - (y + 0.5) / 2.0)
+    }
+
+    fn lookup(texture: &ImageTexture, (u, v): (f32, f32)) -> f32 {
+        let color = texture.value(u, v, Vec3::zero());
+        assert!(color.r() == color.g() && color.g() == color.b());
+        color.r()
+    }
+
+    fn assert_close(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 1e-4, "expected {}, got {}", expected, value);
+    }
+
+    #[test]
+    fn repeat_wraps_negative_coordinates() {
+        let wrapped: Vec<_> = (-7..8).map(|i| WrapMode::Repeat.apply(i, 3)).collect();
+        assert_eq!(wrapped, [2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1, 2, 0, 1]);
+        assert_eq!(WrapMode::Repeat.apply(i64::MIN, 4), 0);
+    }
+
+    #[test]
+    fn clamp_sticks_to_the_edges() {
+        let wrapped: Vec<_> = (-3..6).map(|i| WrapMode::Clamp.apply(i, 3)).collect();
+        assert_eq!(wrapped, [0, 0, 0, 0, 1, 2, 2, 2, 2]);
+        assert_eq!(WrapMode::Clamp.apply(5, 1), 0);
+    }
+
+    #[test]
+    fn mirror_repeats_the_edge_texels() {
+        // Every other copy is flipped, so the texel on each side of an edge is the same
+        let wrapped: Vec<_> = (-7..10).map(|i| WrapMode::Mirror.apply(i, 3)).collect();
+        assert_eq!(wrapped, [0, 0, 1, 2, 2, 1, 0, 0, 1, 2, 2, 1, 0, 0, 1, 2, 2]);
+        assert_eq!(WrapMode::Mirror.apply(-1, 1), 0);
+        assert_eq!(WrapMode::Mirror.apply(2, 1), 0);
+    }
+
+    #[test]
+    fn texel_centers_give_the_texel() {
+        for &filter in &[Filter::Nearest, Filter::Bilinear] {
+            for &wrap in &[WrapMode::Repeat, WrapMode::Clamp, WrapMode::Mirror] {
+                let texture = numbered(wrap, filter);
+                for y in 0..2 {
+                    for x in 0..3 {
+                        let value = lookup(&texture, center(x as f32, y as f32));
+                        assert_close(value, (x + 3 * y) as f32);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_picks_the_texel_under_the_coordinate() {
+        let texture = numbered(WrapMode::Repeat, Filter::Nearest);
+        // v = 1 is the top edge of the first row, u = 1 is already the first column of the next copy
+        assert_close(lookup(&texture, (0.0, 1.0)), 0.0);
+        assert_close(lookup(&texture, (0.999, 0.001)), 5.0);
+        assert_close(lookup(&texture, (1.0, 1.0)), 0.0);
+        assert_close(lookup(&texture, (-0.01, 0.99)), 2.0);
+        assert_close(lookup(&texture, (-0.01, -0.01)), 2.0);
+    }
+
+    #[test]
+    fn bilinear_blends_between_texel_centers() {
+        let texture = numbered(WrapMode::Clamp, Filter::Bilinear);
+        // Halfway between the first two columns, then a quarter of the way down to the second row
+        let (u, v) = center(0.5, 0.0);
+        assert_close(lookup(&texture, (u, v)), 0.5);
+        let (_, v) = center(0.0, 0.25);
+        assert_close(lookup(&texture, (u, v)), 0.5 + 0.75);
+        // Centered on the four inner texels
+        let (u, v) = center(1.5, 0.5);
+        assert_close(lookup(&texture, (u, v)), (1.0 + 2.0 + 4.0 + 5.0) / 4.0);
+    }
+
+    #[test]
+    fn bilinear_wraps_across_the_edges() {
+        // Between the last column and the one after it, on the top row
+        let (u, v) = center(2.5, 0.0);
+        assert_close(lookup(&numbered(WrapMode::Repeat, Filter::Bilinear), (u, v)), (2.0 + 0.0) / 2.0);
+        assert_close(lookup(&numbered(WrapMode::Clamp, Filter::Bilinear), (u, v)), 2.0);
+        assert_close(lookup(&numbered(WrapMode::Mirror, Filter::Bilinear), (u, v)), 2.0);
+
+        // Before the first column
+        let (u, v) = center(-0.25, 1.0);
+        assert_close(lookup(&numbered(WrapMode::Repeat, Filter::Bilinear), (u, v)), 0.25 * 5.0 + 0.75 * 3.0);
+        assert_close(lookup(&numbered(WrapMode::Clamp, Filter::Bilinear), (u, v)), 3.0);
+        assert_close(lookup(&numbered(WrapMode::Mirror, Filter::Bilinear), (u, v)), 3.0);
+
+        // Above the top row, at the corner
+        let (u, v) = center(-0.5, -0.5);
+        assert_close(lookup(&numbered(WrapMode::Repeat, Filter::Bilinear), (u, v)), (5.0 + 3.0 + 2.0 + 0.0) / 4.0);
+        assert_close(lookup(&numbered(WrapMode::Clamp, Filter::Bilinear), (u, v)), 0.0);
+        assert_close(lookup(&numbered(WrapMode::Mirror, Filter::Bilinear), (u, v)), 0.0);
+    }
+
+    #[test]
+    fn hdr_textures_keep_their_range() {
+        let colors = [
+            Vec3::new(1000.0, 500.0, 250.0),
+            Vec3::new(0.5, 0.25, 0.125),
+            Vec3::new(4.0, 0.0, 2.0),
+            Vec3::new(0.0, 0.0, 0.0),
+        ];
+        let mut image = Framebuffer::new(2, 2);
+        image.pixels_mut().copy_from_slice(&colors);
+        let path = std::env::temp_dir().join(format!("rusty_path_texture_{}.hdr", std::process::id()));
+        write_hdr(&mut fs::File::create(&path).unwrap(), &image).unwrap();
+
+        let texture = ImageTexture::load(&path, WrapMode::Repeat, Filter::Nearest).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((texture.width(), texture.height()), (2, 2));
+        for (color, pixel) in colors.iter().zip(&texture.pixels) {
+            for (loaded, expected) in pixel.v().iter().zip(color.v()) {
+                assert!((loaded - expected).abs() <= expected / 100.0, "{:?} loaded as {:?}", color, pixel);
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::vec3::*;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMapOperator {
+    /// Clips every channel at 1.0
+    Clamp,
+    /// L / (1 + L) on luminance, keeps the hue
+    Reinhard,
+    /// Reinhard where luminance `white` maps to 1.0
+    ReinhardExtended { white: f32 },
+    /// Stephen Hill's fit of the ACES RRT and sRGB ODT
+    Aces,
+    /// John Hable's Uncharted 2 filmic curve
+    Hable,
+    /// Polynomial approximation of Blender's AgX
+    AgX,
+}
+
+impl ToneMapOperator {
+    pub fn apply(&self, col: Vec3) -> Vec3 {
+        match *self {
+            ToneMapOperator::Clamp => map_channels(col, |c| clamp(c, 0.0, 1.0)),
+            ToneMapOperator::Reinhard => reinhard(col, None),
+            ToneMapOperator::ReinhardExtended { white } => reinhard(col, Some(white)),
+            ToneMapOperator::Aces => aces(col),
+            ToneMapOperator::Hable => hable(col),
+            ToneMapOperator::AgX => agx(col),
+        }
+    }
+}
+
+/// Exposure and a tone map operator, turns linear radiance into linear display values in 0..1
+#[derive(Copy, Clone, Debug)]
+pub struct ToneMapper {
+    pub operator: ToneMapOperator,
+    /// In EV, every stop doubles the radiance
+    pub exposure: f32,
+}
+
+impl ToneMapper {
+    pub fn new(operator: ToneMapOperator, exposure: f32) -> ToneMapper {
+        ToneMapper {
+            operator,
+            exposure
+        }
+    }
+
+    pub fn map(&self, col: Vec3) -> Vec3 {
+        let exposed = col * 2f32.powf(self.exposure);
+        map_channels(self.operator.apply(exposed), |c| clamp(c, 0.0, 1.0))
+    }
+}
+
+impl Default for ToneMapper {
+    fn default() -> ToneMapper {
+        ToneMapper::new(ToneMapOperator::Clamp, 0.0)
+    }
+}
+
+fn map_channels<F: Fn(f32) -> f32>(col: Vec3, f: F) -> Vec3 {
+    Vec3::new(f(col.r()), f(col.g()), f(col.b()))
+}
+
+/// Rows are the output channels
+fn mul_matrix(m: &[[f32; 3]; 3], col: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * col.r() + m[0][1] * col.g() + m[0][2] * col.b(),
+        m[1][0] * col.r() + m[1][1] * col.g() + m[1][2] * col.b(),
+        m[2][0] * col.r() + m[2][1] * col.g() + m[2][2] * col.b(),
+    )
+}
+
+fn luminance(col: Vec3) -> f32 {
+    0.2126 * col.r() + 0.7152 * col.g() + 0.0722 * col.b()
+}
+
+fn reinhard(col: Vec3, white: Option<f32>) -> Vec3 {
+    let l = luminance(col);
+    if l <= 0.0 {
+        return Vec3::zero();
+    }
+
+    let numerator = match white {
+        Some(white) => l * (1.0 + l / sqr(white)),
+        None => l,
+    };
+    col * (numerator / (1.0 + l) / l)
+}
+
+fn aces(col: Vec3) -> Vec3 {
+    // sRGB => XYZ => D65_2_D60 => AP1 => RRT_SAT
+    const INPUT: [[f32; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    // ODT_SAT => XYZ => D60_2_D65 => sRGB
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+
+    let v = mul_matrix(&INPUT, col);
+    let v = map_channels(v, |v| {
+        let a = v * (v + 0.0245786) - 0.000090537;
+        let b = v * (0.983729 * v + 0.432951) + 0.238081;
+        a / b
+    });
+    mul_matrix(&OUTPUT, v)
+}
+
+fn hable_curve(x: f32) -> f32 {
+    const A: f32 = 0.15; // Shoulder strength
+    const B: f32 = 0.50; // Linear strength
+    const C: f32 = 0.10; // Linear angle
+    const D: f32 = 0.20; // Toe strength
+    const E: f32 = 0.02; // Toe numerator
+    const F: f32 = 0.30; // Toe denominator
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+fn hable(col: Vec3) -> Vec3 {
+    const EXPOSURE_BIAS: f32 = 2.0;
+    const WHITE: f32 = 11.2;
+    let white_scale = 1.0 / hable_curve(WHITE);
+    map_channels(col, |c| hable_curve(c.max(0.0) * EXPOSURE_BIAS) * white_scale)
+}
+
+fn agx(col: Vec3) -> Vec3 {
+    // Inset into the AgX working space
+    const INSET: [[f32; 3]; 3] = [
+        [0.8424791, 0.0784336, 0.07922375],
+        [0.04232824, 0.8784686, 0.07916613],
+        [0.04237565, 0.0784336, 0.879143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196879, -0.09802088, -0.09902974],
+        [-0.05289685, 1.151903, -0.09896118],
+        [-0.05297164, -0.09804345, 1.151074],
+    ];
+    const MIN_EV: f32 = -12.47393;
+    const MAX_EV: f32 = 4.026069;
+
+    let v = mul_matrix(&INSET, map_channels(col, |c| c.max(0.0)));
+    let v = map_channels(v, |c| {
+        // Log2 encoding followed by the sigmoid contrast approximation
+        let x = (clamp(c.log2(), MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.00232
+    });
+    // The curve produces display encoded values, go back to linear for the sRGB encoding
+    map_channels(mul_matrix(&OUTSET, v), |c| c.max(0.0).powf(2.2))
+}
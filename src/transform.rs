@@ -0,0 +1,495 @@
+use crate::vec3::*;
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::*;
+use std::ops;
+use std::sync::Arc;
+
+/// Affine transform, row major with the translation in the last column and 0 0 0 1 as the last row
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Matrix4 {
+    m: [[f32; 4]; 4],
+}
+
+impl Matrix4 {
+    pub fn new(m: [[f32; 4]; 4]) -> Matrix4 {
+        Matrix4 {
+            m
+        }
+    }
+
+    pub fn identity() -> Matrix4 {
+        Matrix4::new([
+            [1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn translation(offset: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [1.0, 0.0, 0.0, offset.x()],
+            [0.0, 1.0, 0.0, offset.y()],
+            [0.0, 0.0, 1.0, offset.z()],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn scaling(scale: Vec3) -> Matrix4 {
+        Matrix4::new([
+            [scale.x(), 0.0, 0.0, 0.0],
+            [0.0, scale.y(), 0.0, 0.0],
+            [0.0, 0.0, scale.z(), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    /// Counter-clockwise rotation by `degrees` when looking against `axis`
+    pub fn rotation(axis: Vec3, degrees: f32) -> Matrix4 {
+        let a = axis.normalized();
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (x, y, z) = (a.x(), a.y(), a.z());
+        let t = 1.0 - cos;
+        Matrix4::new([
+            [t * x * x + cos, t * x * y - sin * z, t * x * z + sin * y, 0.0],
+            [t * x * y + sin * z, t * y * y + cos, t * y * z - sin * x, 0.0],
+            [t * x * z - sin * y, t * y * z + sin * x, t * z * z + cos, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+
+    pub fn get(&self, row: usize, column: usize) -> f32 {
+        self.m[row][column]
+    }
+
+    /// Determinant of the linear part, how much volumes are scaled
+    pub fn determinant(&self) -> f32 {
+        let m = &self.m;
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    /// None for transforms which collapse space, e.g. a zero scale, or whose inverse does not fit into f32.
+    /// Tiny scales are fine, the determinant shrinks with the cube of the scale so no fixed threshold fits all of them.
+    pub fn inverse(&self) -> Option<Matrix4> {
+        let det = self.determinant();
+        if det == 0.0 || !det.is_finite() {
+            return None;
+        }
+
+        // Adjugate of the linear part over the determinant, the translation is moved back through it
+        let m = &self.m;
+        let inv_det = 1.0 / det;
+        let mut inv = [[0.0; 4]; 4];
+        inv[0][0] = (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det;
+        inv[0][1] = (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det;
+        inv[0][2] = (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det;
+        inv[1][0] = (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det;
+        inv[1][1] = (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det;
+        inv[1][2] = (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det;
+        inv[2][0] = (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det;
+        inv[2][1] = (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det;
+        inv[2][2] = (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det;
+        for row in inv.iter_mut().take(3) {
+            row[3] = -(row[0] * m[0][3] + row[1] * m[1][3] + row[2] * m[2][3]);
+        }
+        inv[3][3] = 1.0;
+
+        if inv.iter().flatten().any(|x| !x.is_finite()) {
+            return None;
+        }
+        Some(Matrix4::new(inv))
+    }
+
+    pub fn transform_point(&self, p: &Vec3) -> Vec3 {
+        self.transform_vector(p) + Vec3::new(self.m[0][3], self.m[1][3], self.m[2][3])
+    }
+
+    /// Directions ignore the translation
+    pub fn transform_vector(&self, v: &Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * v.x() + m[0][1] * v.y() + m[0][2] * v.z(),
+            m[1][0] * v.x() + m[1][1] * v.y() + m[1][2] * v.z(),
+            m[2][0] * v.x() + m[2][1] * v.y() + m[2][2] * v.z(),
+        )
+    }
+
+    /// Multiplies by the transpose, called on the inverse this transforms normals, the result is not normalized
+    pub fn transform_normal(&self, n: &Vec3) -> Vec3 {
+        let m = &self.m;
+        Vec3::new(
+            m[0][0] * n.x() + m[1][0] * n.y() + m[2][0] * n.z(),
+            m[0][1] * n.x() + m[1][1] * n.y() + m[2][1] * n.z(),
+            m[0][2] * n.x() + m[1][2] * n.y() + m[2][2] * n.z(),
+        )
+    }
+
+    /// Box around all eight transformed corners
+    pub fn transform_bbox(&self, bbox: &AABB) -> AABB {
+        let mut result = AABB::new_empty();
+        for corner in 0..8 {
+            let p = Vec3::new(
+                if corner & 1 == 0 { bbox.min().x() } else { bbox.max().x() },
+                if corner & 2 == 0 { bbox.min().y() } else { bbox.max().y() },
+                if corner & 4 == 0 { bbox.min().z() } else { bbox.max().z() },
+            );
+            let p = self.transform_point(&p);
+            result.add(&AABB::new(p, p));
+        }
+        result
+    }
+}
+
+/// `a * b` applies b first
+impl ops::Mul for Matrix4 {
+    type Output = Matrix4;
+
+    fn mul(self, other: Matrix4) -> Matrix4 {
+        let mut m = [[0.0; 4]; 4];
+        for (row, out) in m.iter_mut().enumerate() {
+            for (column, value) in out.iter_mut().enumerate() {
+                *value = (0..4).map(|k| self.m[row][k] * other.m[k][column]).sum();
+            }
+        }
+        Matrix4::new(m)
+    }
+}
+
+/// Unit quaternion for rotations which can be interpolated
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Quaternion {
+    w: f32,
+    x: f32,
+    y: f32,
+    z: f32,
+}
+
+impl Quaternion {
+    pub fn identity() -> Quaternion {
+        Quaternion { w: 1.0, x: 0.0, y: 0.0, z: 0.0 }
+    }
+
+    /// Same rotation as `Matrix4::rotation`
+    pub fn from_axis_angle(axis: Vec3, degrees: f32) -> Quaternion {
+        let a = axis.normalized();
+        let (sin, cos) = (degrees.to_radians() * 0.5).sin_cos();
+        Quaternion { w: cos, x: a.x() * sin, y: a.y() * sin, z: a.z() * sin }
+    }
+
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion { w: self.w, x: -self.x, y: -self.y, z: -self.z }
+    }
+
+    fn dot(&self, other: &Quaternion) -> f32 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    fn normalized(&self) -> Quaternion {
+        let len = self.dot(self).sqrt();
+        Quaternion { w: self.w / len, x: self.x / len, y: self.y / len, z: self.z / len }
+    }
+
+    /// Angle in radians of the rotation taking self to other the shorter way
+    pub fn angle_to(&self, other: &Quaternion) -> f32 {
+        2.0 * clamp(self.dot(other).abs(), 0.0, 1.0).acos()
+    }
+
+    /// Constant angular velocity interpolation along the shorter arc
+    pub fn slerp(&self, other: &Quaternion, t: f32) -> Quaternion {
+        let mut cos = self.dot(other);
+        // q and -q are the same rotation, flipping one takes the shorter arc
+        let other = if cos < 0.0 {
+            cos = -cos;
+            Quaternion { w: -other.w, x: -other.x, y: -other.y, z: -other.z }
+        } else {
+            *other
+        };
+
+        let (a, b) = if cos > 0.9995 {
+            // Nearly parallel, the linear blend is accurate and avoids dividing by sin of ~0
+            (1.0 - t, t)
+        } else {
+            let theta = cos.acos();
+            let sin = theta.sin();
+            (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+        };
+        Quaternion {
+            w: a * self.w + b * other.w,
+            x: a * self.x + b * other.x,
+            y: a * self.y + b * other.y,
+            z: a * self.z + b * other.z,
+        }.normalized()
+    }
+
+    pub fn to_matrix(&self) -> Matrix4 {
+        let Quaternion { w, x, y, z } = *self;
+        Matrix4::new([
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y - w * z), 2.0 * (x * z + w * y), 0.0],
+            [2.0 * (x * y + w * z), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z - w * x), 0.0],
+            [2.0 * (x * z - w * y), 2.0 * (y * z + w * x), 1.0 - 2.0 * (x * x + y * y), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
+}
+
+/// Same parametrization, so hits in object space have the same t as in the world
+fn ray_to_object(world_to_object: &Matrix4, r: &Ray) -> Ray {
+    Ray::new(
+        world_to_object.transform_point(r.origin()),
+        world_to_object.transform_vector(r.direction()),
+        r.time()
+    )
+}
+
+/// Hit of a transformed object, shared by everything moving objects by a matrix.
+/// The front face stays the same since the inverse transpose keeps the sign of normal · direction.
+pub(crate) fn hit_transformed(object: &dyn Hittable, object_to_world: &Matrix4, world_to_object: &Matrix4, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+    let hit = object.hit(&ray_to_object(world_to_object, r), t_min, t_max)?;
+    Some(HitRecord {
+        material: hit.material,
+        p: r.point_at(hit.t),
+        // Normals need the inverse transpose to stay perpendicular under non-uniform scale
+        normal: world_to_object.transform_normal(&hit.normal).normalized(),
+        geometric_normal: world_to_object.transform_normal(&hit.geometric_normal).normalized(),
+        front_face: hit.front_face,
+        t: hit.t,
+        uv: hit.uv,
+        dpdu: object_to_world.transform_vector(&hit.dpdu),
+        dpdv: object_to_world.transform_vector(&hit.dpdv)
+    })
+}
+
+pub(crate) fn occluded_transformed(object: &dyn Hittable, world_to_object: &Matrix4, r: &Ray, t_min: f32, t_max: f32) -> bool {
+    object.occluded(&ray_to_object(world_to_object, r), t_min, t_max)
+}
+
+/// The object space density times the change of solid angle, |det A| / |A w|^3 for the linear part A
+pub(crate) fn pdf_value_transformed(object: &dyn Hittable, world_to_object: &Matrix4, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+    let object_origin = world_to_object.transform_point(origin);
+    let object_direction = world_to_object.transform_vector(&direction.normalized());
+    let stretch = object_direction.length();
+    let pdf = object.pdf_value(&object_origin, &object_direction, time);
+    pdf * world_to_object.determinant().abs() / (stretch * stretch * stretch)
+}
+
+pub(crate) fn sample_direction_transformed(object: &dyn Hittable, object_to_world: &Matrix4, world_to_object: &Matrix4, origin: &Vec3, time: f32) -> Option<Vec3> {
+    let object_origin = world_to_object.transform_point(origin);
+    object
+        .sample_direction(&object_origin, time)
+        .map(|direction| object_to_world.transform_vector(&direction))
+}
+
+/// Any hittable placed in the world by a matrix, rays are moved into its space instead of moving the object
+pub struct Transformed {
+    object: Arc<dyn Hittable>,
+    object_to_world: Matrix4,
+    world_to_object: Matrix4,
+}
+
+impl Transformed {
+    /// `transform` takes the object from its own space to the world and has to be invertible
+    pub fn new(object: Arc<dyn Hittable>, transform: Matrix4) -> Transformed {
+        let world_to_object = transform.inverse().expect("Transform is not invertible");
+        Transformed {
+            object,
+            object_to_world: transform,
+            world_to_object
+        }
+    }
+
+    pub fn translate(object: Arc<dyn Hittable>, offset: Vec3) -> Transformed {
+        Transformed::new(object, Matrix4::translation(offset))
+    }
+
+    /// Rotation about `axis` going through the origin
+    pub fn rotate(object: Arc<dyn Hittable>, axis: Vec3, degrees: f32) -> Transformed {
+        Transformed::new(object, Matrix4::rotation(axis, degrees))
+    }
+
+    pub fn scale(object: Arc<dyn Hittable>, scale: Vec3) -> Transformed {
+        Transformed::new(object, Matrix4::scaling(scale))
+    }
+
+    pub fn transform(&self) -> &Matrix4 {
+        &self.object_to_world
+    }
+}
+
+impl Hittable for Transformed {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        hit_transformed(&*self.object, &self.object_to_world, &self.world_to_object, r, t_min, t_max)
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        occluded_transformed(&*self.object, &self.world_to_object, r, t_min, t_max)
+    }
+
+    /// Box around the transformed object box, looser than boxing the transformed object but always conservative
+    fn bouding_box(&self, t0: f32, t1: f32) -> Option<AABB> {
+        self.object.bouding_box(t0, t1).map(|bbox| self.object_to_world.transform_bbox(&bbox))
+    }
+
+    fn pdf_value(&self, origin: &Vec3, direction: &Vec3, time: f32) -> f32 {
+        pdf_value_transformed(&*self.object, &self.world_to_object, origin, direction, time)
+    }
+
+    fn sample_direction(&self, origin: &Vec3, time: f32) -> Option<Vec3> {
+        sample_direction_transformed(&*self.object, &self.object_to_world, &self.world_to_object, origin, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::ConstantTexture;
+
+    fn material() -> Arc<dyn Material> {
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::one()))))
+    }
+
+    fn assert_identity(m: &Matrix4, what: &str) {
+        for row in 0..4 {
+            for column in 0..4 {
+                let expected = if row == column { 1.0 } else { 0.0 };
+                assert!((m.get(row, column) - expected).abs() < 1e-5, "{}: {:?}", what, m);
+            }
+        }
+    }
+
+    /// Rotated, scaled and moved, the translation is far larger than the scale
+    fn placed(scale: Vec3) -> Matrix4 {
+        Matrix4::translation(Vec3::new(3.0, -2.0, 5.0)) * Matrix4::rotation(Vec3::new(1.0, 2.0, 3.0), 40.0) * Matrix4::scaling(scale)
+    }
+
+    #[test]
+    fn inverse_undoes_tiny_and_non_uniform_scales() {
+        let scales = [
+            Vec3::new_fill(1.0),
+            Vec3::new_fill(1e-3),
+            Vec3::new_fill(1e-12),
+            Vec3::new(3.0, 0.5, 0.1),
+            Vec3::new(1e-4, 2e-4, 5e-5),
+            Vec3::new(-2.0, 1.0, 1.0),
+        ];
+        for scale in &scales {
+            let m = placed(*scale);
+            let inverse = m.inverse().unwrap_or_else(|| panic!("{:?} has no inverse", scale));
+            assert_identity(&(m * inverse), &format!("M * inverse for {:?}", scale));
+            assert_identity(&(inverse * m), &format!("inverse * M for {:?}", scale));
+        }
+    }
+
+    #[test]
+    fn collapsed_transforms_have_no_inverse() {
+        assert!(Matrix4::scaling(Vec3::zero()).inverse().is_none());
+        assert!(placed(Vec3::new(1.0, 0.0, 1.0)).inverse().is_none());
+        // Two equal rows collapse space just as well
+        assert!(Matrix4::new([[1.0, 2.0, 3.0, 0.0], [1.0, 2.0, 3.0, 0.0], [0.0, 0.0, 1.0, 0.0], [0.0, 0.0, 0.0, 1.0]]).inverse().is_none());
+        // The determinant underflows to zero
+        assert!(Matrix4::scaling(Vec3::new_fill(1e-20)).inverse().is_none());
+        assert!(Matrix4::scaling(Vec3::new(f32::NAN, 1.0, 1.0)).inverse().is_none());
+    }
+
+    #[test]
+    fn normals_stay_perpendicular_under_non_uniform_scale() {
+        let sphere: Arc<dyn Hittable> = Arc::new(Sphere::new(Vec3::zero(), 1.0, material()));
+        let transform = placed(Vec3::new(3.0, 0.5, 0.2));
+        let ellipsoid = Transformed::new(sphere, transform);
+        let center = transform.transform_point(&Vec3::zero());
+        let mut hits = 0;
+        for i in 0..64 {
+            // Rays from all around aimed a little off the center
+            let angle = i as f32 * 0.4;
+            let origin = center + 10.0 * Vec3::new(angle.cos(), (0.7 * angle).sin(), angle.sin());
+            let target = center + Vec3::new(0.3 * (1.3 * angle).sin(), 0.1 * angle.cos(), 0.05);
+            let hit = match ellipsoid.hit(&Ray::new(origin, target - origin, 0.0), 0.001, f32::MAX) {
+                Some(hit) => hit,
+                None => continue,
+            };
+            hits += 1;
+            assert!((hit.normal.length() - 1.0).abs() < 1e-4);
+            // The tangents follow the surface, so the normal has to be perpendicular to both
+            for tangent in &[hit.dpdu, hit.dpdv] {
+                if tangent.length() > 1e-3 {
+                    assert!(hit.normal.dot(&tangent.normalized()).abs() < 1e-3, "normal {:?} tangent {:?}", hit.normal, tangent);
+                }
+            }
+            assert!(hit.geometric_normal.dot(&(origin - hit.p)) > 0.0, "outside hits face the ray");
+        }
+        assert!(hits > 32, "{} hits", hits);
+    }
+
+    #[test]
+    fn pdf_matches_the_scaled_sphere() {
+        // A transformed unit sphere has to give the same density as a sphere of the scaled radius
+        for &scale in &[2.0, 1e-3] {
+            let center = Vec3::new(1.0, 2.0, 3.0);
+            let scaled = Transformed::new(
+                Arc::new(Sphere::new(Vec3::zero(), 1.0, material())),
+                Matrix4::translation(center) * Matrix4::rotation(Vec3::up(), 30.0) * Matrix4::scaling(Vec3::new_fill(scale))
+            );
+            let sphere = Sphere::new(center, scale, material());
+            let origin = Vec3::new(-4.0, 0.0, 1.0);
+            let direction = center - origin;
+            let expected = sphere.pdf_value(&origin, &direction, 0.0);
+            let pdf = scaled.pdf_value(&origin, &direction, 0.0);
+            assert!(expected > 0.0 && (pdf / expected - 1.0).abs() < 1e-3, "{} vs {} at scale {}", pdf, expected, scale);
+        }
+    }
+
+    /// Same rotation, q and -q included
+    fn assert_same_rotation(a: &Quaternion, b: &Quaternion) {
+        assert!(a.dot(b).abs() > 1.0 - 1e-5, "{:?} vs {:?}", a, b);
+    }
+
+    #[test]
+    fn quaternions_rotate_like_matrices() {
+        let axis = Vec3::new(1.0, -2.0, 0.5);
+        let q = Quaternion::from_axis_angle(axis, 70.0).to_matrix();
+        let m = Matrix4::rotation(axis, 70.0);
+        for row in 0..3 {
+            for column in 0..3 {
+                assert!((q.get(row, column) - m.get(row, column)).abs() < 1e-5, "{:?} vs {:?}", q, m);
+            }
+        }
+    }
+
+    #[test]
+    fn slerp_turns_at_constant_speed() {
+        let a = Quaternion::from_axis_angle(Vec3::up(), 10.0);
+        let b = Quaternion::from_axis_angle(Vec3::up(), 130.0);
+        assert_same_rotation(&a.slerp(&b, 0.0), &a);
+        assert_same_rotation(&a.slerp(&b, 1.0), &b);
+        for &t in &[0.1, 0.25, 0.5, 0.9] {
+            let q = a.slerp(&b, t);
+            assert!((q.dot(&q) - 1.0).abs() < 1e-5);
+            assert_same_rotation(&q, &Quaternion::from_axis_angle(Vec3::up(), 10.0 + 120.0 * t));
+            assert!((a.angle_to(&q) - t * a.angle_to(&b)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn slerp_takes_the_shorter_arc() {
+        // 270 degrees one way is 90 the other way
+        let q = Quaternion::identity().slerp(&Quaternion::from_axis_angle(Vec3::right(), 270.0), 0.5);
+        assert_same_rotation(&q, &Quaternion::from_axis_angle(Vec3::right(), -45.0));
+
+        // -q is the same rotation and must not send the interpolation the long way around
+        let b = Quaternion::from_axis_angle(Vec3::forward(), 60.0);
+        let negated = Quaternion { w: -b.w, x: -b.x, y: -b.y, z: -b.z };
+        assert_same_rotation(&Quaternion::identity().slerp(&negated, 0.5), &Quaternion::from_axis_angle(Vec3::forward(), 30.0));
+    }
+
+    #[test]
+    fn slerp_of_nearly_equal_rotations() {
+        let a = Quaternion::from_axis_angle(Vec3::up(), 20.0);
+        let b = Quaternion::from_axis_angle(Vec3::up(), 20.01);
+        let q = a.slerp(&b, 0.5);
+        assert!((q.dot(&q) - 1.0).abs() < 1e-5);
+        assert_same_rotation(&q, &Quaternion::from_axis_angle(Vec3::up(), 20.005));
+        assert_same_rotation(&a.slerp(&a, 0.3), &a);
+    }
+}
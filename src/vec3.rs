@@ -2,6 +2,7 @@ extern crate rand;
 
 use rand::Rng;
 use std::ops;
+use crate::random;
 
 pub fn sqr(x: f32) -> f32 {
     x * x
@@ -105,7 +106,7 @@ impl Vec3 {
     }
 
     pub fn length_sqr(&self) -> f32 {
-        (self.v[0] * self.v[0] + self.v[1] * self.v[1] + self.v[2] * self.v[2])
+        self.v[0] * self.v[0] + self.v[1] * self.v[1] + self.v[2] * self.v[2]
     }
 
     pub fn length(&self) -> f32 {
@@ -137,11 +138,11 @@ impl Vec3 {
         }
     }
 
-    pub fn to_srgb(&self) -> Vec3 {
+    pub fn to_srgb(self) -> Vec3 {
         Vec3::new(to_srgb(self.r()), to_srgb(self.g()), to_srgb(self.b()))
     }
 
-    pub fn to_linear(&self) -> Vec3 {
+    pub fn to_linear(self) -> Vec3 {
         Vec3::new(to_linear(self.r()), to_linear(self.g()), to_linear(self.b()))
     }
 
@@ -161,7 +162,7 @@ impl Vec3 {
     }
 }
 
-impl<'a> ops::Add<&Vec3> for &'a Vec3 {
+impl ops::Add<&Vec3> for &Vec3 {
     type Output = Vec3;
     
     fn add(self, other: &Vec3) -> Vec3 {
@@ -253,14 +254,14 @@ impl ops::Neg for &Vec3 {
 impl ops::Sub for Vec3 {
     type Output = Self;
     fn sub(self, other: Self) -> Self {
-        &self + &(-other)
+        self + -other
     }
 }
 
-impl<'a, 'b> ops::Sub<&'a Vec3> for &'b Vec3 {
+impl ops::Sub<&Vec3> for &Vec3 {
     type Output = Vec3;
-    fn sub(self, other: &'a Vec3) -> Vec3 {
-        self + &(-other)
+    fn sub(self, other: &Vec3) -> Vec3 {
+        self + -other
     }
 }
 
@@ -356,7 +357,7 @@ impl ops::DivAssign<f32> for Vec3 {
 }
 
 pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
+    let mut rng = random::rng();
     loop {
         let p = 2.0 * Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) - Vec3::one();
         if p.length_sqr() < 1.0 {
@@ -365,9 +366,36 @@ pub fn random_in_unit_sphere() -> Vec3 {
     }
 }
 
+/// Uniformly distributed direction, added to a normal it gives a cosine distributed direction
+pub fn random_unit_vector() -> Vec3 {
+    random_in_unit_sphere().normalized()
+}
+
+/// Direction inside the cone around `axis` whose half angle has a cosine of `1 - one_minus_cos_theta_max`,
+/// passing the difference keeps narrow cones of far away lights from collapsing onto the axis
+pub fn random_in_cone(axis: &Vec3, one_minus_cos_theta_max: f32) -> Vec3 {
+    let mut rng = random::rng();
+    let r1 = rng.gen::<f32>();
+    let r2 = rng.gen::<f32>();
+    let one_minus_z = r2 * one_minus_cos_theta_max;
+    let z = 1.0 - one_minus_z;
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let sin_theta = (one_minus_z * (2.0 - one_minus_z)).max(0.0).sqrt();
+    let (u, v) = orthonormal_basis(axis);
+    phi.cos() * sin_theta * u + phi.sin() * sin_theta * v + z * axis
+}
+
+/// Two unit vectors perpendicular to the unit vector `w` and to each other
+pub fn orthonormal_basis(w: &Vec3) -> (Vec3, Vec3) {
+    let a = if w.x().abs() > 0.9 { Vec3::up() } else { Vec3::right() };
+    let v = w.cross(&a).normalized();
+    let u = w.cross(&v);
+    (u, v)
+}
+
 /// Returns a random point in unit circle with center in origin
 pub fn random_in_unit_circle() -> Vec3 {
-    let mut rng = rand::thread_rng();
+    let mut rng = random::rng();
     loop {
         let p = 2.0 * Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), 0.0) - Vec3::new(1.0, 1.0, 0.0);
         if p.length_sqr() < 1.0 {
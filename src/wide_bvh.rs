@@ -0,0 +1,448 @@
+use crate::ray::Ray;
+use crate::aabb::AABB;
+use crate::hittable::{Hittable, HitRecord};
+use crate::bvh::{build_tree, object_bboxes, BuildNode, BVHSettings, MAX_DEPTH};
+use std::sync::Arc;
+
+/// Instruction set used for the node box tests
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SimdLevel {
+    Scalar,
+    /// Four boxes per instruction
+    Sse,
+    /// Eight boxes per instruction, BVH4 nodes use SSE
+    Avx2,
+}
+
+impl SimdLevel {
+    /// Best level the CPU running this supports
+    pub fn detect() -> SimdLevel {
+        #[cfg(target_arch = "x86_64")]
+        {
+            if is_x86_feature_detected!("avx2") {
+                return SimdLevel::Avx2;
+            }
+            if is_x86_feature_detected!("sse2") {
+                return SimdLevel::Sse;
+            }
+        }
+        SimdLevel::Scalar
+    }
+}
+
+/// Set on child references pointing into the leaf table instead of the node array
+const LEAF_BIT: u32 = 1 << 31;
+/// Unused child slot, its box is empty so it is never hit
+const EMPTY: u32 = u32::MAX;
+
+/// Bounds of all children as structure of arrays: min x, y, z then max x, y, z
+#[repr(C, align(32))]
+struct WideNode<const N: usize> {
+    bounds: [[f32; N]; 6],
+    children: [u32; N],
+}
+
+struct Leaf {
+    first: u32,
+    count: u32,
+}
+
+/// Per ray values shared by all box tests
+struct RayData {
+    origin: [f32; 3],
+    inv_dir: [f32; 3],
+    /// Row of `bounds` the ray enters through on each axis, the other one is where it leaves
+    near: [usize; 3],
+    far: [usize; 3],
+}
+
+impl RayData {
+    fn new(r: &Ray) -> RayData {
+        let dir = r.direction().v();
+        let inv_dir = [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]];
+        let mut near = [0, 1, 2];
+        let mut far = [3, 4, 5];
+        for a in 0..3 {
+            if inv_dir[a] < 0.0 {
+                std::mem::swap(&mut near[a], &mut far[a]);
+            }
+        }
+
+        RayData {
+            origin: *r.origin().v(),
+            inv_dir,
+            near,
+            far
+        }
+    }
+}
+
+/// BVH with `N` children per node collapsed from the binary SAH tree, N is 4 or 8
+pub struct WideBVH<const N: usize> {
+    nodes: Vec<WideNode<N>>,
+    leaves: Vec<Leaf>,
+    primitives: Vec<Arc<dyn Hittable>>,
+    bbox: AABB,
+    simd: SimdLevel,
+}
+
+pub type BVH4 = WideBVH<4>;
+pub type BVH8 = WideBVH<8>;
+
+impl<const N: usize> WideBVH<N> {
+    /// Bounding boxes cover the time interval t0..t1. Fails when an object has none,
+    /// `Aggregate` keeps such objects next to its BVH instead
+    pub fn new(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32) -> Result<WideBVH<N>, String> {
+        WideBVH::with_settings(objects, t0, t1, &BVHSettings::default())
+    }
+
+    pub fn with_settings(objects: &[Arc<dyn Hittable>], t0: f32, t1: f32, settings: &BVHSettings) -> Result<WideBVH<N>, String> {
+        assert!(N == 4 || N == 8, "WideBVH supports 4 or 8 children per node");
+        let bboxes = object_bboxes(objects, t0, t1)?;
+        let tree = build_tree(&bboxes, settings);
+
+        let mut bvh = WideBVH {
+            nodes: vec![],
+            leaves: vec![],
+            primitives: tree.order.iter().map(|&i| objects[i].clone()).collect(),
+            bbox: *node_bbox(&tree.root),
+            simd: SimdLevel::detect()
+        };
+        match &tree.root {
+            // A single leaf still gets a root node so traversal always starts at a node
+            BuildNode::Leaf { .. } => {
+                bvh.push_node(&[&tree.root]);
+            },
+            BuildNode::Inner { .. } => {
+                bvh.collapse(&tree.root);
+            },
+        }
+        Ok(bvh)
+    }
+
+    /// Overrides the detected instruction set, e.g. for comparing against the scalar path
+    pub fn set_simd_level(&mut self, level: SimdLevel) {
+        assert!(level <= SimdLevel::detect(), "{:?} is not supported by this CPU", level);
+        self.simd = level;
+    }
+
+    pub fn simd_level(&self) -> SimdLevel {
+        self.simd
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Pulls grandchildren up until the node is full, largest children are opened first
+    fn collapse(&mut self, node: &BuildNode) -> u32 {
+        let mut children: Vec<&BuildNode> = match node {
+            BuildNode::Inner { left, right, .. } => vec![left, right],
+            BuildNode::Leaf { .. } => unreachable!("Leaves are not collapsed"),
+        };
+
+        while children.len() < N {
+            let largest = children.iter()
+                .enumerate()
+                .filter(|(_, child)| matches!(child, BuildNode::Inner { .. }))
+                .max_by(|(_, a), (_, b)| node_bbox(a).surface_area().total_cmp(&node_bbox(b).surface_area()))
+                .map(|(i, _)| i);
+            match largest {
+                Some(i) => {
+                    if let BuildNode::Inner { left, right, .. } = children[i] {
+                        children[i] = left;
+                        children.insert(i + 1, right);
+                    }
+                },
+                None => break,
+            }
+        }
+
+        self.push_node(&children)
+    }
+
+    fn push_node(&mut self, children: &[&BuildNode]) -> u32 {
+        let index = self.nodes.len();
+        self.nodes.push(WideNode {
+            bounds: [[f32::MAX; N], [f32::MAX; N], [f32::MAX; N], [f32::MIN; N], [f32::MIN; N], [f32::MIN; N]],
+            children: [EMPTY; N]
+        });
+
+        for (lane, child) in children.iter().enumerate() {
+            let reference = match child {
+                BuildNode::Leaf { first, count, .. } => {
+                    self.leaves.push(Leaf { first: *first as u32, count: *count as u32 });
+                    LEAF_BIT | (self.leaves.len() - 1) as u32
+                },
+                BuildNode::Inner { .. } => self.collapse(child),
+            };
+
+            let bbox = node_bbox(child);
+            let node = &mut self.nodes[index];
+            for a in 0..3 {
+                node.bounds[a][lane] = bbox.min().v()[a];
+                node.bounds[3 + a][lane] = bbox.max().v()[a];
+            }
+            node.children[lane] = reference;
+        }
+        index as u32
+    }
+
+    /// Bit per child whose box the ray enters within t_min..t_max, `t_near` gets the entry distances
+    fn intersect_node(&self, node: &WideNode<N>, ray: &RayData, t_min: f32, t_max: f32, t_near: &mut [f32; N]) -> u32 {
+        match self.simd {
+            // Only selected when detected, see SimdLevel::detect
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 if N == 8 => unsafe { x86::intersect_avx2(&node.bounds, ray, t_min, t_max, t_near) },
+            #[cfg(target_arch = "x86_64")]
+            SimdLevel::Avx2 | SimdLevel::Sse => unsafe { x86::intersect_sse(&node.bounds, ray, t_min, t_max, t_near) },
+            _ => intersect_scalar(&node.bounds, ray, t_min, t_max, t_near),
+        }
+    }
+
+    fn leaf_primitives(&self, reference: u32) -> &[Arc<dyn Hittable>] {
+        let leaf = &self.leaves[(reference & !LEAF_BIT) as usize];
+        &self.primitives[leaf.first as usize..(leaf.first + leaf.count) as usize]
+    }
+}
+
+fn node_bbox(node: &BuildNode) -> &AABB {
+    match node {
+        BuildNode::Leaf { bbox, .. } => bbox,
+        BuildNode::Inner { bbox, .. } => bbox,
+    }
+}
+
+fn intersect_scalar<const N: usize>(bounds: &[[f32; N]; 6], ray: &RayData, t_min: f32, t_max: f32, t_near: &mut [f32; N]) -> u32 {
+    let mut mask = 0;
+    for lane in 0..N {
+        let mut lo = t_min;
+        let mut hi = t_max;
+        for a in 0..3 {
+            // max and min ignore the NaN of a ray running inside a slab plane
+            lo = ((bounds[ray.near[a]][lane] - ray.origin[a]) * ray.inv_dir[a]).max(lo);
+            hi = ((bounds[ray.far[a]][lane] - ray.origin[a]) * ray.inv_dir[a]).min(hi);
+        }
+        t_near[lane] = lo;
+        if lo <= hi {
+            mask |= 1 << lane;
+        }
+    }
+    mask
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::RayData;
+    use std::arch::x86_64::*;
+
+    /// Same as `intersect_scalar`, `_mm_max_ps` returns its second operand for NaN which gives the same results
+    #[target_feature(enable = "sse2")]
+    pub(super) unsafe fn intersect_sse<const N: usize>(bounds: &[[f32; N]; 6], ray: &RayData, t_min: f32, t_max: f32, t_near: &mut [f32; N]) -> u32 {
+        let mut mask = 0;
+        for lane in (0..N).step_by(4) {
+            let mut lo = _mm_set1_ps(t_min);
+            let mut hi = _mm_set1_ps(t_max);
+            for a in 0..3 {
+                let origin = _mm_set1_ps(ray.origin[a]);
+                let inv_dir = _mm_set1_ps(ray.inv_dir[a]);
+                let near = _mm_loadu_ps(bounds[ray.near[a]].as_ptr().add(lane));
+                let far = _mm_loadu_ps(bounds[ray.far[a]].as_ptr().add(lane));
+                lo = _mm_max_ps(_mm_mul_ps(_mm_sub_ps(near, origin), inv_dir), lo);
+                hi = _mm_min_ps(_mm_mul_ps(_mm_sub_ps(far, origin), inv_dir), hi);
+            }
+            _mm_storeu_ps(t_near.as_mut_ptr().add(lane), lo);
+            mask |= (_mm_movemask_ps(_mm_cmple_ps(lo, hi)) as u32) << lane;
+        }
+        mask
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn intersect_avx2<const N: usize>(bounds: &[[f32; N]; 6], ray: &RayData, t_min: f32, t_max: f32, t_near: &mut [f32; N]) -> u32 {
+        let mut mask = 0;
+        for lane in (0..N).step_by(8) {
+            let mut lo = _mm256_set1_ps(t_min);
+            let mut hi = _mm256_set1_ps(t_max);
+            for a in 0..3 {
+                let origin = _mm256_set1_ps(ray.origin[a]);
+                let inv_dir = _mm256_set1_ps(ray.inv_dir[a]);
+                let near = _mm256_loadu_ps(bounds[ray.near[a]].as_ptr().add(lane));
+                let far = _mm256_loadu_ps(bounds[ray.far[a]].as_ptr().add(lane));
+                lo = _mm256_max_ps(_mm256_mul_ps(_mm256_sub_ps(near, origin), inv_dir), lo);
+                hi = _mm256_min_ps(_mm256_mul_ps(_mm256_sub_ps(far, origin), inv_dir), hi);
+            }
+            _mm256_storeu_ps(t_near.as_mut_ptr().add(lane), lo);
+            mask |= (_mm256_movemask_ps(_mm256_cmp_ps::<_CMP_LE_OQ>(lo, hi)) as u32) << lane;
+        }
+        mask
+    }
+}
+
+impl<const N: usize> Hittable for WideBVH<N> {
+    fn hit(&self, r: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
+        let ray = RayData::new(r);
+        // Child references with their entry distance, every level pushes at most N - 1 of them besides the one visited next
+        let mut stack = [(0u32, 0f32); MAX_DEPTH * 7 + 1];
+        let mut stack_size = 1;
+        let mut closest = t_max;
+        let mut result = None;
+        let mut t_near = [0.0; N];
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let (reference, entry) = stack[stack_size];
+            // Something closer was found since the child was pushed
+            if entry > closest {
+                continue;
+            }
+
+            if reference & LEAF_BIT != 0 {
+                for primitive in self.leaf_primitives(reference) {
+                    if let Some(hit) = primitive.hit(r, t_min, closest) {
+                        closest = hit.t;
+                        result = Some(hit);
+                    }
+                }
+                continue;
+            }
+
+            let node = &self.nodes[reference as usize];
+            let mut mask = self.intersect_node(node, &ray, t_min, closest, &mut t_near);
+            // Push far to near so the nearest child is visited first
+            let first = stack_size;
+            while mask != 0 {
+                let lane = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                let mut i = stack_size;
+                while i > first && stack[i - 1].1 < t_near[lane] {
+                    stack[i] = stack[i - 1];
+                    i -= 1;
+                }
+                stack[i] = (node.children[lane], t_near[lane]);
+                stack_size += 1;
+            }
+        }
+
+        result
+    }
+
+    fn occluded(&self, r: &Ray, t_min: f32, t_max: f32) -> bool {
+        let ray = RayData::new(r);
+        let mut stack = [0u32; MAX_DEPTH * 7 + 1];
+        let mut stack_size = 1;
+        let mut t_near = [0.0; N];
+
+        while stack_size > 0 {
+            stack_size -= 1;
+            let reference = stack[stack_size];
+            if reference & LEAF_BIT != 0 {
+                if self.leaf_primitives(reference).iter().any(|p| p.occluded(r, t_min, t_max)) {
+                    return true;
+                }
+                continue;
+            }
+
+            let node = &self.nodes[reference as usize];
+            let mut mask = self.intersect_node(node, &ray, t_min, t_max, &mut t_near);
+            while mask != 0 {
+                let lane = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                stack[stack_size] = node.children[lane];
+                stack_size += 1;
+            }
+        }
+
+        false
+    }
+
+    fn bouding_box(&self, _t0: f32, _t1: f32) -> Option<AABB> {
+        Some(self.bbox)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bvh::tests::{assert_same_hits, test_objects, test_rays};
+    use crate::hittable::{Lambertian, Material};
+    use crate::quad::Quad;
+    use crate::texture::ConstantTexture;
+    use crate::vec3::Vec3;
+    use crate::random;
+
+    /// Every level up to the detected one, so the SIMD paths are checked wherever the CPU has them
+    fn supported_levels() -> Vec<SimdLevel> {
+        [SimdLevel::Scalar, SimdLevel::Sse, SimdLevel::Avx2].iter().cloned().filter(|&level| level <= SimdLevel::detect()).collect()
+    }
+
+    fn check<const N: usize>(objects: &[Arc<dyn Hittable>], rays: &[Ray], settings: &BVHSettings) {
+        let mut bvh = WideBVH::<N>::with_settings(objects, 0.0, 1.0, settings).unwrap();
+        for level in supported_levels() {
+            bvh.set_simd_level(level);
+            assert_same_hits(&format!("BVH{} {:?}", N, level), &bvh, objects, rays);
+        }
+    }
+
+    #[test]
+    fn wide_hierarchies_match_testing_every_object() {
+        random::seed(3);
+        let objects = test_objects(500);
+        let rays = test_rays(2000, 0.0, 1.0);
+        check::<4>(&objects, &rays, &BVHSettings::default());
+        check::<8>(&objects, &rays, &BVHSettings::default());
+    }
+
+    #[test]
+    fn wide_hierarchies_with_partly_filled_nodes() {
+        // A handful of objects leaves empty child slots, large leaves put several objects in one
+        random::seed(4);
+        for &count in &[2, 5, 11] {
+            let objects = test_objects(count);
+            // Aimed close to the objects, random rays would rarely hit so few of them
+            let rays: Vec<Ray> = test_rays(1000, 0.0, 1.0).iter().enumerate().map(|(i, r)| {
+                let target = objects[i % count].bouding_box(0.0, 1.0).unwrap().centroid();
+                Ray::new(*r.origin(), target + *r.direction() - *r.origin(), r.time())
+            }).collect();
+            check::<4>(&objects, &rays, &BVHSettings::default());
+            check::<8>(&objects, &rays, &BVHSettings::default());
+        }
+        let objects = test_objects(200);
+        let rays = test_rays(1000, 0.0, 1.0);
+        let settings = BVHSettings { max_leaf_size: 16, ..BVHSettings::default() };
+        check::<4>(&objects, &rays, &settings);
+        check::<8>(&objects, &rays, &settings);
+    }
+
+    #[test]
+    fn axis_rays_starting_on_box_planes() {
+        // Quads are only padded across their plane, so rays along their edges start on box planes
+        // where 0 * inf in the slab test gives NaN, which every path has to treat the same way.
+        // Quads facing x and z put the NaN on every axis, the last one included
+        let material: Arc<dyn Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(Vec3::one()))));
+        let back = -Vec3::forward();
+        let mut objects: Vec<Arc<dyn Hittable>> = vec![];
+        for i in 0..4 {
+            for j in 0..4 {
+                for k in 0..4 {
+                    let origin = Vec3::new(2.0 * i as f32, 2.0 * j as f32, 2.0 * k as f32);
+                    objects.push(Arc::new(Quad::new(origin, Vec3::right(), Vec3::up(), material.clone())));
+                    objects.push(Arc::new(Quad::new(origin + Vec3::new(0.5, 0.0, 0.0), Vec3::up(), back, material.clone())));
+                }
+            }
+        }
+        let mut rays = vec![];
+        for i in 0..4 {
+            for j in 0..4 {
+                for &(a, b) in &[(0.0, 0.5), (1.0, 0.5), (0.5, 0.0), (0.5, 1.0), (0.0, 0.0), (1.5, 0.5), (0.5, 1.5)] {
+                    let (a, b) = (2.0 * i as f32 + a, 2.0 * j as f32 + b);
+                    rays.push(Ray::new(Vec3::new(a, b, 10.0), Vec3::forward(), 0.0));
+                    rays.push(Ray::new(Vec3::new(a, b, -3.0), back, 0.0));
+                    rays.push(Ray::new(Vec3::new(-3.0, b, a), Vec3::right(), 0.0));
+                    rays.push(Ray::new(Vec3::new(10.0, b, a), -Vec3::right(), 0.0));
+                }
+            }
+        }
+        check::<4>(&objects, &rays, &BVHSettings::default());
+        check::<8>(&objects, &rays, &BVHSettings::default());
+    }
+}